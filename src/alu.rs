@@ -34,7 +34,11 @@ pub fn alu_add(reg: &mut Registers, n: u8) {
 // ADC A, n
 pub fn alu_adc(reg: &mut Registers, n: u8) {
     let c: u8 = reg.get_flag_bit(Flag::C);
-    alu_add(reg, n + c);
+    let r = reg.a.wrapping_add(n).wrapping_add(c);
+    let h = (reg.a & 0x0F) + (n & 0x0F) + c > 0x0F;
+    let carry = reg.a as u16 + n as u16 + c as u16 > 0xFF;
+    reg.set_flags(r == 0, false, h, carry);
+    reg.a = r;
 }
 
 // SUB A, n
@@ -47,7 +51,11 @@ pub fn alu_sub(reg: &mut Registers, n: u8) {
 // SBC A, n
 pub fn alu_sbc(reg: &mut Registers, n: u8) {
     let c: u8 = reg.get_flag_bit(Flag::C);
-    alu_sub(reg, n + c);
+    let r = reg.a.wrapping_sub(n).wrapping_sub(c);
+    let h = (reg.a & 0x0F) < (n & 0x0F) + c;
+    let carry = (reg.a as u16) < n as u16 + c as u16;
+    reg.set_flags(r == 0, true, h, carry);
+    reg.a = r;
 }
 
 // AND n
@@ -98,13 +106,47 @@ pub fn alu_dec(reg: &mut Registers, n: u8) -> u8 {
 pub fn alu_add_hl(reg: &mut Registers, n: u16) {
     let r = reg.hl().wrapping_add(n);
     reg.set_flag(Flag::N, false);
-    reg.set_flag(Flag::H, (reg.hl() & 0x07FF) + (n & 0x07FF) > 0x07FF);
+    reg.set_flag(Flag::H, (reg.hl() & 0x0FFF) + (n & 0x0FFF) > 0x0FFF);
     reg.set_flag(Flag::C, ((reg.hl() as u32) + (n as u32)) > 0xFFFF);
     reg.set_hl(r);
 }
 
+// Returns SP + e for ADD SP, e and LD HL, SP + e, flags come from the low byte
+pub fn alu_add_sp(reg: &mut Registers, e: i8) -> u16 {
+    let sp = reg.sp;
+    let n = e as u8 as u16;
+    reg.set_flags(false, false, (sp & 0x0F) + (n & 0x0F) > 0x0F, (sp & 0xFF) + n > 0xFF);
+    sp.wrapping_add(e as u16)
+}
+
 /// Misc
 
+// DAA, corrects A to BCD after an addition or subtraction
+pub fn alu_daa(reg: &mut Registers) {
+    let mut a = reg.a;
+    let mut carry = reg.get_flag(Flag::C);
+    if reg.get_flag(Flag::N) {
+        if carry {
+            a = a.wrapping_sub(0x60);
+        }
+        if reg.get_flag(Flag::H) {
+            a = a.wrapping_sub(0x06);
+        }
+    } else {
+        if carry || a > 0x99 {
+            a = a.wrapping_add(0x60);
+            carry = true;
+        }
+        if reg.get_flag(Flag::H) || a & 0x0F > 0x09 {
+            a = a.wrapping_add(0x06);
+        }
+    }
+    reg.set_flag(Flag::Z, a == 0);
+    reg.set_flag(Flag::H, false);
+    reg.set_flag(Flag::C, carry);
+    reg.a = a;
+}
+
 // SWAP n
 pub fn alu_swap(reg: &mut Registers, n: u8) -> u8 {
     let r = (n & 0xF0) >> 4 | (n & 0x0F) << 4;
@@ -121,6 +163,27 @@ pub fn alu_rlc(reg: &mut Registers, n: u8) -> u8 {
     r
 }
 
+// RRC n
+pub fn alu_rrc(reg: &mut Registers, n: u8) -> u8 {
+    let r = n.rotate_right(1);
+    reg.set_flags(r == 0, false, false, n & 0x01 == 0x01);
+    r
+}
+
+// RL n, through the carry flag
+pub fn alu_rl(reg: &mut Registers, n: u8) -> u8 {
+    let r = n << 1 | reg.get_flag_bit(Flag::C);
+    reg.set_flags(r == 0, false, false, n & 0x80 == 0x80);
+    r
+}
+
+// RR n, through the carry flag
+pub fn alu_rr(reg: &mut Registers, n: u8) -> u8 {
+    let r = n >> 1 | reg.get_flag_bit(Flag::C) << 7;
+    reg.set_flags(r == 0, false, false, n & 0x01 == 0x01);
+    r
+}
+
 // SLA n
 pub fn alu_sla(reg: &mut Registers, n: u8) -> u8 {
     let r = (n << 1) & 0xFE;
@@ -128,6 +191,20 @@ pub fn alu_sla(reg: &mut Registers, n: u8) -> u8 {
     r
 }
 
+// SRA n, keeps bit 7
+pub fn alu_sra(reg: &mut Registers, n: u8) -> u8 {
+    let r = n >> 1 | (n & 0x80);
+    reg.set_flags(r == 0, false, false, n & 0x01 == 0x01);
+    r
+}
+
+// SRL n
+pub fn alu_srl(reg: &mut Registers, n: u8) -> u8 {
+    let r = n >> 1;
+    reg.set_flags(r == 0, false, false, n & 0x01 == 0x01);
+    r
+}
+
 /// Bit Opcodes
 
 // BIT b, r
@@ -142,6 +219,11 @@ pub fn alu_set(r: u8, b: u8) -> u8 {
     r | (1 << b)
 }
 
+// RES b, r
+pub fn alu_res(r: u8, b: u8) -> u8 {
+    r & !(1 << b)
+}
+
 
 #[cfg(test)]
 mod tests {
@@ -187,6 +269,16 @@ mod tests {
         assert_eq!(reg.f, 0);
     }
 
+    #[test]
+    fn test_alu_adc_carry() {
+        let mut reg = Registers::new();
+        reg.f = 0b00010000;
+        reg.a = 0x0F;
+        alu_adc(&mut reg, 0xF0);
+        assert_eq!(reg.a, 0x00);
+        assert_eq!(reg.f, 0b10110000);
+    }
+
     #[test]
     fn test_alu_sub() {
         let mut reg = Registers::new();
@@ -207,6 +299,16 @@ mod tests {
         assert_eq!(reg.f, 0b01110000);
     }
 
+    #[test]
+    fn test_alu_sbc_carry() {
+        let mut reg = Registers::new();
+        reg.f = 0b00010000;
+        reg.a = 0x00;
+        alu_sbc(&mut reg, 0xFF);
+        assert_eq!(reg.a, 0x00);
+        assert_eq!(reg.f, 0b11110000);
+    }
+
     #[test]
     fn test_alu_and() {
         let mut reg = Registers::new();
@@ -280,6 +382,32 @@ mod tests {
 		assert!(!reg.get_flag(Flag::C));
 	}
 
+    #[test]
+    fn test_alu_add_sp() {
+        let mut reg = Registers::new();
+        reg.sp = 0xFFF8;
+        assert_eq!(alu_add_sp(&mut reg, 0x08), 0x0000);
+        assert_eq!(reg.f, 0b00110000);
+        assert_eq!(alu_add_sp(&mut reg, -1), 0xFFF7);
+        assert_eq!(reg.f, 0b00110000);
+    }
+
+    #[test]
+    fn test_alu_daa() {
+        let mut reg = Registers::new();
+        reg.f = 0;
+        reg.a = 0x45;
+        alu_add(&mut reg, 0x38);
+        alu_daa(&mut reg);
+        assert_eq!(reg.a, 0x83);
+        assert_eq!(reg.f, 0b00000000);
+
+        alu_sub(&mut reg, 0x84);
+        alu_daa(&mut reg);
+        assert_eq!(reg.a, 0x99);
+        assert_eq!(reg.f, 0b01010000);
+    }
+
     #[test]
     fn test_alu_swap() {
         let mut reg = Registers::new();
@@ -298,6 +426,18 @@ mod tests {
         assert_eq!(reg.f, 0b00010000);
     }
 
+    #[test]
+    fn test_alu_rotate_through_carry() {
+        let mut reg = Registers::new();
+        reg.f = 0b00010000;
+        let n = alu_rl(&mut reg, 0x40);
+        assert_eq!(n, 0x81);
+        assert_eq!(reg.f, 0);
+        let n = alu_rr(&mut reg, 0x01);
+        assert_eq!(n, 0x00);
+        assert_eq!(reg.f, 0b10010000);
+    }
+
     #[test]
     fn test_alu_sla() {
         let mut reg = Registers::new();
@@ -307,6 +447,15 @@ mod tests {
         assert_eq!(reg.f, 0b00010000);
     }
 
+    #[test]
+    fn test_alu_shift_right() {
+        let mut reg = Registers::new();
+        assert_eq!(alu_sra(&mut reg, 0x81), 0xC0);
+        assert_eq!(reg.f, 0b00010000);
+        assert_eq!(alu_srl(&mut reg, 0x81), 0x40);
+        assert_eq!(reg.f, 0b00010000);
+    }
+
     #[test]
     fn test_alu_bit() {
         let mut reg = Registers::new();
@@ -320,5 +469,6 @@ mod tests {
         let mut r = 0x01;
         r = alu_set(r, 1);
         assert_eq!(r, 0x03);
+        assert_eq!(alu_res(r, 0), 0x02);
     }
 }
\ No newline at end of file
@@ -0,0 +1,1203 @@
+use crate::model::*;
+use crate::mixer::*;
+use crate::audio_sink::*;
+use crate::vgm::*;
+
+use log::warn;
+use std::io;
+use std::path::Path;
+
+// Audio processing unit.
+//
+// Two pulse channels (the first with a frequency sweep), a wave channel
+// playing 32 4-bit samples from wave RAM and a noise channel driven by an
+// LFSR. A frame sequencer clocked at 512 Hz by the falling edge of DIV bit 4
+// (bit 5 in double speed) steps length counters, envelopes and the sweep.
+// https://gbdev.io/pandocs/Audio.html
+//
+// Register quirks follow blargg's dmg_sound and cgb_sound tests: NR52 power
+// off, the extra length clock on NRx4 writes, "zombie" envelope writes and
+// wave RAM access while channel 3 plays.
+//
+// The APU lives in Memory so register writes can reach it, and is ticked
+// once per normal speed m-cycle like the PPU. Every tick is mixed through
+// NR51 and NR50 and the high-pass filter, then resampled to the output rate.
+// All writes to 0xFF10-0xFF3F pass through `write`, which makes it the place
+// to log them for VGM files.
+
+pub const M_CYCLES_PER_SECOND: u32 = 1 << 20;
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+
+// Stereo frames handed to the sink at once
+const SINK_BATCH: usize = 512;
+
+// Channel output history for oscilloscope views, one entry every
+// HISTORY_INTERVAL m-cycles, 15.6 ms in total
+pub const HISTORY_LEN: usize = 1024;
+const HISTORY_INTERVAL: u8 = 16;
+
+// Bits that read back as 1 for 0xFF10-0xFF2F, wave RAM reads as written
+const READ_MASKS: [u8; 0x20] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // NR20-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // NR40-NR44
+    0x00, 0x00, 0x70,             // NR50-NR52
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF
+];
+
+const DUTY_PATTERNS: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1], // 12.5%
+    [1, 0, 0, 0, 0, 0, 0, 1], // 25%
+    [1, 0, 0, 0, 0, 1, 1, 1], // 50%
+    [0, 1, 1, 1, 1, 1, 1, 0]  // 75%
+];
+
+// Length counter, silences the channel when it runs out
+#[derive(Debug, Clone, Copy)]
+struct Length {
+    counter: u16,
+    max: u16,
+    enabled: bool
+}
+
+impl Length {
+    fn new(max: u16) -> Length {
+        Length { counter: 0, max, enabled: false }
+    }
+
+    fn load(&mut self, val: u8) {
+        self.counter = self.max - val as u16;
+    }
+
+    // NRx4 write. Enabling the counter while the next frame sequencer step
+    // doesn't clock length clocks it once more, a trigger reloading an empty
+    // counter then starts from max - 1. Returns true when that extra clock
+    // ran the counter out without a trigger.
+    fn write_control(&mut self, val: u8, first_half: bool) -> bool {
+        let was_enabled = self.enabled;
+        let trigger = val & 0x80 != 0;
+        self.enabled = val & 0x40 != 0;
+
+        let mut expired = false;
+        if first_half && !was_enabled && self.enabled && self.counter != 0 {
+            self.counter -= 1;
+            expired = self.counter == 0;
+        }
+
+        if trigger && self.counter == 0 {
+            self.counter = match self.enabled && first_half {
+                true => self.max - 1,
+                false => self.max
+            };
+        }
+        expired && !trigger
+    }
+
+    // Returns true when the counter just ran out
+    fn clock(&mut self) -> bool {
+        if !self.enabled || self.counter == 0 {
+            return false;
+        }
+        self.counter -= 1;
+        self.counter == 0
+    }
+}
+
+// Volume envelope of the pulse and noise channels, NRx2
+#[derive(Debug, Clone, Copy)]
+struct Envelope {
+    initial: u8,
+    increase: bool,
+    period: u8,
+    volume: u8,
+    timer: u8,
+    // Stops once the volume can't go any further
+    running: bool
+}
+
+impl Envelope {
+    fn new() -> Envelope {
+        Envelope { initial: 0, increase: false, period: 0, volume: 0, timer: 0, running: false }
+    }
+
+    // NRx2 writes while the channel plays change the volume right away
+    fn zombie_write(&mut self, val: u8) {
+        let mut volume = self.volume;
+        if self.period == 0 && self.running {
+            volume += 1;
+        } else if !self.increase {
+            volume += 2;
+        }
+        if (val & 0x08 != 0) != self.increase {
+            volume = 16u8.wrapping_sub(volume);
+        }
+        self.volume = volume & 0x0F;
+    }
+
+    fn write(&mut self, val: u8) {
+        self.initial = val >> 4;
+        self.increase = val & 0x08 != 0;
+        self.period = val & 0x07;
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.initial;
+        self.timer = self.period;
+        self.running = true;
+    }
+
+    fn clock(&mut self) {
+        if self.period == 0 || !self.running {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = self.period;
+            match self.increase {
+                true if self.volume < 15 => self.volume += 1,
+                false if self.volume > 0 => self.volume -= 1,
+                _ => self.running = false
+            }
+        }
+    }
+}
+
+// Frequency sweep of channel 1, NR10
+#[derive(Debug, Clone, Copy)]
+struct Sweep {
+    period: u8,
+    negate: bool,
+    shift: u8,
+    timer: u8,
+    shadow: u16,
+    enabled: bool,
+    // A calculation was done in negate mode since the last trigger
+    negated: bool
+}
+
+impl Sweep {
+    fn new() -> Sweep {
+        Sweep { period: 0, negate: false, shift: 0, timer: 0, shadow: 0, enabled: false, negated: false }
+    }
+
+    // Returns false when leaving negate mode after it was used, which
+    // silences the channel
+    fn write(&mut self, val: u8) -> bool {
+        self.period = (val >> 4) & 0x07;
+        self.negate = val & 0x08 != 0;
+        self.shift = val & 0x07;
+        !self.negated || self.negate
+    }
+
+    // A period of 0 is treated as 8 by the timer
+    fn reload(&mut self) {
+        self.timer = if self.period == 0 { 8 } else { self.period };
+    }
+
+    // Next frequency, above 2047 silences the channel
+    fn next_freq(&mut self) -> u16 {
+        let delta = self.shadow >> self.shift;
+        self.negated |= self.negate;
+        match self.negate {
+            true => self.shadow.wrapping_sub(delta),
+            false => self.shadow + delta
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Pulse {
+    enabled: bool,
+    dac_enabled: bool,
+    length: Length,
+    envelope: Envelope,
+    sweep: Option<Sweep>,
+    duty: u8,
+    position: u8,
+    freq: u16,
+    timer: u32
+}
+
+impl Pulse {
+    fn new(with_sweep: bool) -> Pulse {
+        Pulse {
+            enabled: false,
+            dac_enabled: false,
+            length: Length::new(64),
+            envelope: Envelope::new(),
+            sweep: if with_sweep { Some(Sweep::new()) } else { None },
+            duty: 0,
+            position: 0,
+            freq: 0,
+            timer: 0
+        }
+    }
+
+    fn period(&self) -> u32 {
+        (2048 - self.freq as u32) * 4
+    }
+
+    fn write(&mut self, reg: u16, val: u8, first_half: bool) {
+        match reg {
+            0 => if let Some(sweep) = self.sweep.as_mut() {
+                self.enabled &= sweep.write(val);
+            },
+            1 => {
+                self.duty = val >> 6;
+                self.length.load(val & 0x3F);
+            },
+            2 => {
+                if self.enabled {
+                    self.envelope.zombie_write(val);
+                }
+                self.envelope.write(val);
+                self.dac_enabled = val & 0xF8 != 0;
+                self.enabled &= self.dac_enabled;
+            },
+            3 => self.freq = (self.freq & 0x0700) | val as u16,
+            _ => {
+                self.freq = (self.freq & 0x00FF) | ((val & 0x07) as u16) << 8;
+                if self.length.write_control(val, first_half) {
+                    self.enabled = false;
+                }
+                if val & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.envelope.trigger();
+        self.timer = self.period();
+
+        if let Some(sweep) = self.sweep.as_mut() {
+            sweep.shadow = self.freq;
+            sweep.negated = false;
+            sweep.reload();
+            sweep.enabled = sweep.period != 0 || sweep.shift != 0;
+            if sweep.shift != 0 && sweep.next_freq() > 2047 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.position = (self.position + 1) % 8;
+        }
+        self.timer -= cycles;
+    }
+
+    fn clock_sweep(&mut self) {
+        let Some(sweep) = self.sweep.as_mut() else { return };
+        sweep.timer = sweep.timer.saturating_sub(1);
+        if sweep.timer != 0 {
+            return;
+        }
+        sweep.reload();
+        if !sweep.enabled || sweep.period == 0 {
+            return;
+        }
+
+        let freq = sweep.next_freq();
+        if freq > 2047 {
+            self.enabled = false;
+        } else if sweep.shift != 0 {
+            sweep.shadow = freq;
+            self.freq = freq;
+            // Checked again with the new frequency
+            if sweep.next_freq() > 2047 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn output(&self) -> u8 {
+        DUTY_PATTERNS[self.duty as usize][self.position as usize] * self.envelope.volume
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Wave {
+    enabled: bool,
+    dac_enabled: bool,
+    length: Length,
+    volume_shift: u8,
+    position: u8,
+    sample: u8,
+    freq: u16,
+    timer: u32,
+    // A sample was fetched during the last m-cycle
+    just_read: bool,
+    ram: [u8; 16]
+}
+
+impl Wave {
+    fn new() -> Wave {
+        Wave {
+            enabled: false,
+            dac_enabled: false,
+            length: Length::new(256),
+            volume_shift: 4,
+            position: 0,
+            sample: 0,
+            freq: 0,
+            timer: 0,
+            just_read: false,
+            ram: [0; 16]
+        }
+    }
+
+    fn period(&self) -> u32 {
+        (2048 - self.freq as u32) * 2
+    }
+
+    fn write(&mut self, reg: u16, val: u8, first_half: bool) {
+        match reg {
+            0 => {
+                self.dac_enabled = val & 0x80 != 0;
+                self.enabled &= self.dac_enabled;
+            },
+            1 => self.length.load(val),
+            // 0 mutes, 1-3 play at 100%, 50% and 25%
+            2 => self.volume_shift = [4, 0, 1, 2][((val >> 5) & 0x03) as usize],
+            3 => self.freq = (self.freq & 0x0700) | val as u16,
+            _ => {
+                self.freq = (self.freq & 0x00FF) | ((val & 0x07) as u16) << 8;
+                if self.length.write_control(val, first_half) {
+                    self.enabled = false;
+                }
+                if val & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+        }
+    }
+
+    // While the channel plays only the byte being played is reachable, and
+    // on DMG only right as it's fetched
+    fn ram_index(&self, offset: usize, cgb: bool) -> Option<usize> {
+        match (self.enabled, cgb || self.just_read) {
+            (false, _) => Some(offset),
+            (true, true) => Some(self.position as usize / 2),
+            (true, false) => None
+        }
+    }
+
+    fn read_ram(&self, offset: usize, cgb: bool) -> u8 {
+        self.ram_index(offset, cgb).map_or(0xFF, |i| self.ram[i])
+    }
+
+    fn write_ram(&mut self, offset: usize, val: u8, cgb: bool) {
+        if let Some(i) = self.ram_index(offset, cgb) {
+            self.ram[i] = val;
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.position = 0;
+        self.timer = self.period();
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        let mut cycles = cycles;
+        self.just_read = false;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.just_read = true;
+            self.position = (self.position + 1) % 32;
+            let byte = self.ram[self.position as usize / 2];
+            self.sample = if self.position.is_multiple_of(2) { byte >> 4 } else { byte & 0x0F };
+        }
+        self.timer -= cycles;
+    }
+
+    fn output(&self) -> u8 {
+        self.sample >> self.volume_shift
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Noise {
+    enabled: bool,
+    dac_enabled: bool,
+    length: Length,
+    envelope: Envelope,
+    shift: u8,
+    short_mode: bool,
+    divisor: u8,
+    lfsr: u16,
+    timer: u32
+}
+
+impl Noise {
+    fn new() -> Noise {
+        Noise {
+            enabled: false,
+            dac_enabled: false,
+            length: Length::new(64),
+            envelope: Envelope::new(),
+            shift: 0,
+            short_mode: false,
+            divisor: 0,
+            lfsr: 0x7FFF,
+            timer: 0
+        }
+    }
+
+    fn period(&self) -> u32 {
+        let divisor = if self.divisor == 0 { 8 } else { self.divisor as u32 * 16 };
+        divisor << self.shift
+    }
+
+    fn write(&mut self, reg: u16, val: u8, first_half: bool) {
+        match reg {
+            0 => {},
+            1 => self.length.load(val & 0x3F),
+            2 => {
+                if self.enabled {
+                    self.envelope.zombie_write(val);
+                }
+                self.envelope.write(val);
+                self.dac_enabled = val & 0xF8 != 0;
+                self.enabled &= self.dac_enabled;
+            },
+            3 => {
+                self.shift = val >> 4;
+                self.short_mode = val & 0x08 != 0;
+                self.divisor = val & 0x07;
+            },
+            _ => {
+                if self.length.write_control(val, first_half) {
+                    self.enabled = false;
+                }
+                if val & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.envelope.trigger();
+        self.lfsr = 0x7FFF;
+        self.timer = self.period();
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+
+            let xor = (self.lfsr ^ (self.lfsr >> 1)) & 0x01;
+            self.lfsr = (self.lfsr >> 1) | (xor << 14);
+            if self.short_mode {
+                self.lfsr = (self.lfsr & !0x40) | (xor << 6);
+            }
+        }
+        self.timer -= cycles;
+    }
+
+    fn output(&self) -> u8 {
+        (!self.lfsr & 0x01) as u8 * self.envelope.volume
+    }
+}
+
+// Current state of a channel, for debugging views
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChannelInfo {
+    pub enabled: bool,
+    pub dac_enabled: bool,
+    // Tone frequency in Hz, for noise the LFSR clock rate
+    pub frequency: f32,
+    // 0-15, the wave channel reports its volume code 0-3
+    pub volume: u8,
+    // Duty pattern 0-3 of the pulse channels
+    pub duty: Option<u8>,
+    // Length counter and whether it's enabled
+    pub length: u16,
+    pub length_enabled: bool
+}
+
+pub struct Apu {
+    // Last written values of 0xFF10-0xFF3F
+    regs: [u8; 0x30],
+    enabled: bool,
+    // CGB hardware, which differs in power off and wave RAM access
+    cgb: bool,
+    pub ch1: Pulse,
+    pub ch2: Pulse,
+    pub ch3: Wave,
+    pub ch4: Noise,
+    frame_step: u8,
+    div_bit: bool,
+    high_pass: HighPass,
+    resampler: Resampler,
+    // Channels left out of the mix, muted or not soloed
+    muted: [bool; 4],
+    solo: Option<usize>,
+    // Ring buffers of channel outputs, `history_pos` is the oldest entry
+    history: [[u8; HISTORY_LEN]; 4],
+    history_pos: usize,
+    history_timer: u8,
+    // Interleaved stereo samples waiting to be taken, or pushed to the sink
+    samples: Vec<f32>,
+    sink: Option<Box<dyn AudioSink>>,
+    // Normal speed m-cycles since power on, timestamps for the VGM log
+    cycles: u64,
+    vgm: Option<VgmWriter>
+}
+
+impl Apu {
+    pub fn with_model(model: Model) -> Apu {
+        Apu {
+            regs: [0; 0x30],
+            enabled: false,
+            cgb: model.is_cgb(),
+            ch1: Pulse::new(true),
+            ch2: Pulse::new(false),
+            ch3: Wave::new(),
+            ch4: Noise::new(),
+            frame_step: 0,
+            div_bit: false,
+            high_pass: HighPass::new(model.is_cgb(), M_CYCLES_PER_SECOND),
+            resampler: Resampler::new(M_CYCLES_PER_SECOND, DEFAULT_SAMPLE_RATE),
+            muted: [false; 4],
+            solo: None,
+            history: [[0; HISTORY_LEN]; 4],
+            history_pos: 0,
+            history_timer: 0,
+            samples: Vec::new(),
+            sink: None,
+            cycles: 0,
+            vgm: None
+        }
+    }
+
+    pub fn set_sample_rate(&mut self, rate: u32) {
+        self.resampler = Resampler::new(M_CYCLES_PER_SECOND, rate);
+    }
+
+    // Send samples to `sink` in batches instead of keeping them for take_samples.
+    // A previous sink gets what's left for it first.
+    pub fn set_sink(&mut self, sink: Box<dyn AudioSink>) {
+        self.flush();
+        self.sink = Some(sink);
+    }
+
+    // Push the samples short of a batch to the sink, also done on drop
+    pub fn flush(&mut self) {
+        if let Some(sink) = self.sink.as_mut() {
+            if !self.samples.is_empty() {
+                sink.push(&self.samples);
+                self.samples.clear();
+            }
+        }
+    }
+
+    // Log register writes from now on, starting with the current state
+    pub fn start_vgm<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let mut vgm = VgmWriter::create(path, self.cycles)?;
+        vgm.write(self.cycles, 0xFF26, (self.enabled as u8) << 7);
+        for (i, val) in self.ch3.ram.iter().enumerate() {
+            vgm.write(self.cycles, 0xFF30 + i as u16, *val);
+        }
+        // Without triggering, channels start playing with the next trigger
+        for (i, val) in self.regs[..0x16].iter().enumerate() {
+            let adr = 0xFF10 + i as u16;
+            let val = if i % 5 == 4 { val & 0x7F } else { *val };
+            vgm.write(self.cycles, adr, val);
+        }
+        self.vgm = Some(vgm);
+        Ok(())
+    }
+
+    pub fn stop_vgm(&mut self) {
+        if let Some(mut vgm) = self.vgm.take() {
+            if let Err(why) = vgm.finish(self.cycles) {
+                warn!("couldn't finish VGM file: {}", why);
+            }
+        }
+    }
+
+    // Continue the output of `old`, which this APU replaces: sink, sample
+    // rate, channel muting and VGM log
+    pub fn take_output(&mut self, old: &mut Apu) {
+        std::mem::swap(&mut self.resampler, &mut old.resampler);
+        std::mem::swap(&mut self.samples, &mut old.samples);
+        self.sink = old.sink.take();
+        self.muted = old.muted;
+        self.solo = old.solo;
+        self.cycles = old.cycles;
+        self.vgm = old.vgm.take();
+    }
+
+    // Frames queued in a realtime sink
+    pub fn queued_audio(&self) -> Option<usize> {
+        self.sink.as_ref().and_then(|sink| sink.queued())
+    }
+
+    // Dynamic rate control, see pacing.rs
+    pub fn set_rate_adjust(&mut self, ratio: f64) {
+        self.resampler.set_ratio(ratio);
+    }
+
+    pub fn rate_adjust(&self) -> f64 {
+        self.resampler.ratio()
+    }
+
+    // Channels are numbered 0-3 for channel 1-4
+    pub fn set_muted(&mut self, channel: usize, muted: bool) {
+        self.muted[channel] = muted;
+    }
+
+    pub fn is_muted(&self, channel: usize) -> bool {
+        self.muted[channel]
+    }
+
+    // Only play `channel`, or all unmuted ones again with None
+    pub fn set_solo(&mut self, channel: Option<usize>) {
+        self.solo = channel;
+    }
+
+    pub fn solo(&self) -> Option<usize> {
+        self.solo
+    }
+
+    fn audible(&self, channel: usize) -> bool {
+        match self.solo {
+            Some(solo) => solo == channel,
+            None => !self.muted[channel]
+        }
+    }
+
+    pub fn channel_info(&self, channel: usize) -> ChannelInfo {
+        const CLOCK: f32 = 4194304.0;
+        let pulse = |ch: &Pulse| ChannelInfo {
+            enabled: ch.enabled,
+            dac_enabled: ch.dac_enabled,
+            frequency: CLOCK / (ch.period() * 8) as f32,
+            volume: ch.envelope.volume,
+            duty: Some(ch.duty),
+            length: ch.length.counter,
+            length_enabled: ch.length.enabled
+        };
+        match channel {
+            0 => pulse(&self.ch1),
+            1 => pulse(&self.ch2),
+            2 => ChannelInfo {
+                enabled: self.ch3.enabled,
+                dac_enabled: self.ch3.dac_enabled,
+                frequency: CLOCK / (self.ch3.period() * 32) as f32,
+                // NR32 code for the shift it was stored as
+                volume: match self.ch3.volume_shift {
+                    0 => 1,
+                    1 => 2,
+                    2 => 3,
+                    _ => 0
+                },
+                duty: None,
+                length: self.ch3.length.counter,
+                length_enabled: self.ch3.length.enabled
+            },
+            _ => ChannelInfo {
+                enabled: self.ch4.enabled,
+                dac_enabled: self.ch4.dac_enabled,
+                frequency: CLOCK / self.ch4.period() as f32,
+                volume: self.ch4.envelope.volume,
+                duty: None,
+                length: self.ch4.length.counter,
+                length_enabled: self.ch4.length.enabled
+            }
+        }
+    }
+
+    // Recent outputs 0-15 of `channel`, oldest first. Muting doesn't affect it.
+    pub fn history(&self, channel: usize) -> Vec<u8> {
+        let history = &self.history[channel];
+        [&history[self.history_pos..], &history[..self.history_pos]].concat()
+    }
+
+    // Samples produced since the last call, interleaved left and right
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+
+    // Register values left behind by the boot rom, without triggering anything
+    pub fn load_registers(&mut self, regs: &[u8]) {
+        self.set_power(regs[0x16] & 0x80 != 0);
+        for (i, val) in regs.iter().enumerate() {
+            let adr = 0xFF10 + i as u16;
+            let val = if (adr - 0xFF10) % 5 == 4 && adr < 0xFF24 { val & 0x7F } else { *val };
+            self.write(adr, val);
+        }
+        self.ch1.enabled = regs[0x16] & 0x01 != 0 && self.ch1.dac_enabled;
+        self.ch2.enabled = regs[0x16] & 0x02 != 0 && self.ch2.dac_enabled;
+        self.ch3.enabled = regs[0x16] & 0x04 != 0 && self.ch3.dac_enabled;
+        self.ch4.enabled = regs[0x16] & 0x08 != 0 && self.ch4.dac_enabled;
+    }
+
+    pub fn read(&self, adr: u16) -> u8 {
+        match adr {
+            // NR52 - power and channel status
+            0xFF26 => {
+                let status = [self.ch1.enabled, self.ch2.enabled, self.ch3.enabled, self.ch4.enabled]
+                    .iter().enumerate()
+                    .fold(0, |status, (i, on)| status | (*on as u8) << i);
+                ((self.enabled as u8) << 7) | 0x70 | status
+            },
+            0xFF30..=0xFF3F => self.ch3.read_ram((adr - 0xFF30) as usize, self.cgb),
+            _ => self.regs[(adr - 0xFF10) as usize] | READ_MASKS[(adr - 0xFF10) as usize]
+        }
+    }
+
+    pub fn write(&mut self, adr: u16, val: u8) {
+        if let Some(vgm) = self.vgm.as_mut() {
+            vgm.write(self.cycles, adr, val);
+        }
+
+        match adr {
+            0xFF26 => return self.set_power(val & 0x80 != 0),
+            0xFF30..=0xFF3F => return self.ch3.write_ram((adr - 0xFF30) as usize, val, self.cgb),
+            _ => {}
+        }
+
+        // Powered off only NR52 and wave RAM are writable, and on DMG the
+        // length counters
+        if !self.enabled {
+            if !self.cgb {
+                match adr {
+                    0xFF11 => self.ch1.length.load(val & 0x3F),
+                    0xFF16 => self.ch2.length.load(val & 0x3F),
+                    0xFF1B => self.ch3.length.load(val),
+                    0xFF20 => self.ch4.length.load(val & 0x3F),
+                    _ => {}
+                }
+            }
+            return;
+        }
+
+        // The next frame sequencer step doesn't clock length
+        let first_half = !self.frame_step.is_multiple_of(2);
+        match adr {
+            0xFF10..=0xFF14 => self.ch1.write(adr - 0xFF10, val, first_half),
+            0xFF15..=0xFF19 => self.ch2.write(adr - 0xFF15, val, first_half),
+            0xFF1A..=0xFF1E => self.ch3.write(adr - 0xFF1A, val, first_half),
+            0xFF1F..=0xFF23 => self.ch4.write(adr - 0xFF1F, val, first_half),
+            _ => {}
+        }
+        self.regs[(adr - 0xFF10) as usize] = val;
+    }
+
+    // NR52 bit 7. Powering off clears every register but wave RAM, and the
+    // length counters on DMG. Powering on restarts the frame sequencer.
+    fn set_power(&mut self, on: bool) {
+        if on && !self.enabled {
+            self.frame_step = 0;
+        }
+
+        if !on && self.enabled {
+            let lengths = [self.ch1.length, self.ch2.length, self.ch3.length, self.ch4.length];
+            let ram = self.ch3.ram;
+            self.ch1 = Pulse::new(true);
+            self.ch2 = Pulse::new(false);
+            self.ch3 = Wave::new();
+            self.ch4 = Noise::new();
+            self.ch3.ram = ram;
+
+            if !self.cgb {
+                self.ch1.length.counter = lengths[0].counter;
+                self.ch2.length.counter = lengths[1].counter;
+                self.ch3.length.counter = lengths[2].counter;
+                self.ch4.length.counter = lengths[3].counter;
+            }
+            self.regs[..0x16].fill(0);
+        }
+        self.enabled = on;
+    }
+
+    // One normal speed m-cycle
+    pub fn tick(&mut self, div: u16, double_speed: bool) {
+        self.cycles += 1;
+        let div_bit = div & if double_speed { 0x2000 } else { 0x1000 } != 0;
+        if self.div_bit && !div_bit && self.enabled {
+            self.step_frame_sequencer();
+        }
+        self.div_bit = div_bit;
+
+        if self.enabled {
+            self.ch1.tick(4);
+            self.ch2.tick(4);
+            self.ch3.tick(4);
+            self.ch4.tick(4);
+        }
+
+        let outputs = self.outputs();
+        self.history_timer += 1;
+        if self.history_timer == HISTORY_INTERVAL {
+            self.history_timer = 0;
+            for (history, out) in self.history.iter_mut().zip(outputs) {
+                history[self.history_pos] = out.unwrap_or(0);
+            }
+            self.history_pos = (self.history_pos + 1) % HISTORY_LEN;
+        }
+
+        let frame = self.high_pass.apply(self.mix(&outputs), outputs.iter().any(Option::is_some));
+        self.resampler.push(frame, &mut self.samples);
+
+        if let Some(sink) = self.sink.as_mut() {
+            if self.samples.len() >= SINK_BATCH * 2 {
+                sink.push(&self.samples);
+                self.samples.clear();
+            }
+        }
+    }
+
+    fn step_frame_sequencer(&mut self) {
+        // Length at 256 Hz, sweep at 128 Hz, envelope at 64 Hz
+        if self.frame_step.is_multiple_of(2) {
+            self.ch1.enabled &= !self.ch1.length.clock();
+            self.ch2.enabled &= !self.ch2.length.clock();
+            self.ch3.enabled &= !self.ch3.length.clock();
+            self.ch4.enabled &= !self.ch4.length.clock();
+        }
+        if self.frame_step == 2 || self.frame_step == 6 {
+            self.ch1.clock_sweep();
+        }
+        if self.frame_step == 7 {
+            self.ch1.envelope.clock();
+            self.ch2.envelope.clock();
+            self.ch4.envelope.clock();
+        }
+        self.frame_step = (self.frame_step + 1) % 8;
+    }
+
+    // Digital outputs 0-15 of the four channels, 0 when silenced
+    fn outputs(&self) -> [Option<u8>; 4] {
+        let output = |dac_enabled: bool, enabled: bool, output: u8| {
+            dac_enabled.then_some(if enabled { output } else { 0 })
+        };
+        [
+            output(self.ch1.dac_enabled, self.ch1.enabled, self.ch1.output()),
+            output(self.ch2.dac_enabled, self.ch2.enabled, self.ch2.output()),
+            output(self.ch3.dac_enabled, self.ch3.enabled, self.ch3.output()),
+            output(self.ch4.dac_enabled, self.ch4.enabled, self.ch4.output())
+        ]
+    }
+
+    // Each DAC maps 0-15 to 1.0 down to -1.0, a disabled DAC outputs nothing.
+    // Muted channels are left out. NR51 routes the channels to the left (bits 4-7) and right (bits 0-3)
+    // side, NR50 sets the volume of each side from 1/8 to 8/8.
+    fn mix(&self, outputs: &[Option<u8>; 4]) -> [f32; 2] {
+        let nr50 = self.regs[0x14];
+        let nr51 = self.regs[0x15];
+
+        let mut frame = [0.0; 2];
+        for (i, out) in outputs.iter().enumerate() {
+            if !self.audible(i) {
+                continue;
+            }
+            let analog = out.map_or(0.0, |out| 1.0 - out as f32 / 7.5);
+            if nr51 & (0x10 << i) != 0 {
+                frame[0] += analog;
+            }
+            if nr51 & (0x01 << i) != 0 {
+                frame[1] += analog;
+            }
+        }
+
+        let left = ((nr50 >> 4) & 0x07) as f32 + 1.0;
+        let right = (nr50 & 0x07) as f32 + 1.0;
+        [frame[0] / 4.0 * left / 8.0, frame[1] / 4.0 * right / 8.0]
+    }
+}
+
+impl Drop for Apu {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    fn powered_apu() -> Apu {
+        let mut apu = Apu::with_model(Model::Dmg);
+        apu.write(0xFF26, 0x80);
+        apu
+    }
+
+    // Run the frame sequencer `steps` times
+    fn run_frames(apu: &mut Apu, steps: u32) {
+        for _ in 0..steps {
+            apu.tick(0x1000, false);
+            apu.tick(0x0000, false);
+        }
+    }
+
+    #[test]
+    fn test_pulse_duty() {
+        let mut apu = powered_apu();
+        apu.write(0xFF16, 0x80); // 50% duty
+        apu.write(0xFF17, 0xF0);
+        apu.write(0xFF18, 0xFF);
+        apu.write(0xFF19, 0x87); // freq 0x7FF, trigger
+
+        // One duty step every 4 cycles
+        let mut highs = 0;
+        for _ in 0..8 {
+            apu.tick(0, false);
+            highs += (apu.ch2.output() > 0) as u32;
+        }
+        assert_eq!(highs, 4);
+        assert_eq!(apu.read(0xFF26), 0xF2);
+    }
+
+    #[test]
+    fn test_length_counter() {
+        let mut apu = powered_apu();
+        apu.write(0xFF12, 0xF0);
+        apu.write(0xFF11, 0x3E); // 2 steps left
+        apu.write(0xFF14, 0xC0);
+        assert_eq!(apu.read(0xFF26) & 0x01, 0x01);
+
+        // Length is clocked on every other step
+        run_frames(&mut apu, 3);
+        assert_eq!(apu.read(0xFF26) & 0x01, 0x00);
+    }
+
+    #[test]
+    fn test_envelope() {
+        let mut apu = powered_apu();
+        apu.write(0xFF21, 0xA1); // Volume 10, decreasing every step
+        apu.write(0xFF23, 0x80);
+        run_frames(&mut apu, 8);
+        assert_eq!(apu.ch4.envelope.volume, 9);
+    }
+
+    #[test]
+    fn test_sweep_overflow() {
+        let mut apu = powered_apu();
+        apu.write(0xFF12, 0xF0);
+        apu.write(0xFF10, 0x11); // Period 1, shift 1, increasing
+        apu.write(0xFF13, 0x00);
+        apu.write(0xFF14, 0x86); // freq 0x600 + 0x300 overflows at once
+        assert_eq!(apu.read(0xFF26) & 0x01, 0x00);
+
+        apu.write(0xFF14, 0x84); // freq 0x400 + 0x200 is fine, 0x600 after one sweep
+        assert_eq!(apu.read(0xFF26) & 0x01, 0x01);
+        run_frames(&mut apu, 3);
+        assert_eq!(apu.ch1.freq, 0x600);
+        assert_eq!(apu.read(0xFF26) & 0x01, 0x00);
+    }
+
+    #[test]
+    fn test_read_masks() {
+        let apu = Apu::with_model(Model::Dmg);
+        assert_eq!(apu.read(0xFF10), 0x80);
+        assert_eq!(apu.read(0xFF1A), 0x7F);
+        assert_eq!(apu.read(0xFF26), 0x70);
+        assert_eq!(apu.read(0xFF2F), 0xFF);
+    }
+
+    #[test]
+    fn test_power_off() {
+        for model in [Model::Dmg, Model::Cgb] {
+            let mut apu = Apu::with_model(model);
+            apu.write(0xFF26, 0x80);
+            apu.write(0xFF30, 0x12);
+            apu.write(0xFF12, 0xF0);
+            apu.write(0xFF11, 0xBE);
+            apu.write(0xFF24, 0x77);
+
+            apu.write(0xFF26, 0x00);
+            assert_eq!(apu.read(0xFF11), 0x3F);
+            assert_eq!(apu.read(0xFF24), 0x00);
+            assert_eq!(apu.read(0xFF30), 0x12);
+
+            // Ignored while off, except the DMG's length counters
+            apu.write(0xFF12, 0xF0);
+            apu.write(0xFF20, 0x3F);
+            assert_eq!(apu.read(0xFF12), 0x00);
+            assert_eq!(apu.ch1.length.counter, if model == Model::Dmg { 2 } else { 0 });
+            assert_eq!(apu.ch4.length.counter, if model == Model::Dmg { 1 } else { 0 });
+        }
+    }
+
+    #[test]
+    fn test_extra_length_clock() {
+        let mut apu = powered_apu();
+        apu.write(0xFF12, 0xF0);
+        apu.write(0xFF11, 0x3F); // 1 step left
+        apu.write(0xFF14, 0x80);
+        run_frames(&mut apu, 1);
+
+        // The next step doesn't clock length, enabling it does
+        apu.write(0xFF14, 0x40);
+        assert_eq!(apu.read(0xFF26) & 0x01, 0x00);
+
+        // Triggering with an empty counter loads 63 instead of 64
+        apu.write(0xFF14, 0xC0);
+        assert_eq!(apu.ch1.length.counter, 63);
+    }
+
+    #[test]
+    fn test_zombie_envelope() {
+        let mut apu = powered_apu();
+        apu.write(0xFF17, 0x51); // Volume 5, decreasing
+        apu.write(0xFF19, 0x80);
+
+        // Decreasing mode adds 2
+        apu.write(0xFF17, 0x51);
+        assert_eq!(apu.ch2.envelope.volume, 7);
+        // Switching to increase mode also flips the volume
+        apu.write(0xFF17, 0x59);
+        assert_eq!(apu.ch2.envelope.volume, 7);
+        // No period while running adds 1
+        apu.write(0xFF17, 0x08);
+        apu.write(0xFF17, 0x08);
+        assert_eq!(apu.ch2.envelope.volume, 8);
+    }
+
+    #[test]
+    fn test_wave_ram_while_playing() {
+        for model in [Model::Dmg, Model::Cgb] {
+            let mut apu = Apu::with_model(model);
+            apu.write(0xFF26, 0x80);
+            apu.write(0xFF30, 0x11);
+            apu.write(0xFF31, 0x22);
+            apu.write(0xFF1A, 0x80);
+            apu.write(0xFF1D, 0x00);
+            apu.write(0xFF1E, 0x80); // Period of 4096 cycles
+
+            // Position 0 plays, other bytes aren't reachable
+            apu.tick(0, false);
+            match model {
+                Model::Dmg => assert_eq!(apu.read(0xFF31), 0xFF),
+                _ => assert_eq!(apu.read(0xFF31), 0x11)
+            }
+        }
+    }
+
+    #[test]
+    fn test_noise_lfsr() {
+        let mut noise = Noise::new();
+        noise.write(3, 0x08, false); // Short mode, divisor 8
+        noise.trigger();
+        noise.tick(8);
+        assert_eq!(noise.lfsr, 0x3FBF);
+    }
+
+    #[test]
+    fn test_samples() {
+        let mut apu = powered_apu();
+        apu.set_sample_rate(48000);
+        for _ in 0..M_CYCLES_PER_SECOND / 8 {
+            apu.tick(0, false);
+        }
+        // Less the resampler's latency
+        let len = apu.take_samples().len() / 2;
+        assert!(len > 5900 && len <= 6000);
+        assert!(apu.take_samples().is_empty());
+    }
+
+    struct CountingSink(Rc<Cell<usize>>);
+
+    impl AudioSink for CountingSink {
+        fn push(&mut self, samples: &[f32]) {
+            self.0.set(self.0.get() + samples.len());
+        }
+    }
+
+    #[test]
+    fn test_sink() {
+        let count = Rc::new(Cell::new(0));
+        let mut apu = powered_apu();
+        apu.set_sink(Box::new(CountingSink(count.clone())));
+        for _ in 0..M_CYCLES_PER_SECOND / 8 {
+            apu.tick(0, false);
+        }
+        assert_eq!(count.get() % (SINK_BATCH * 2), 0);
+        assert!(count.get() / 2 > 5000);
+
+        // The rest goes out when the APU is dropped
+        let pending = apu.samples.len();
+        assert!(pending > 0 && pending < SINK_BATCH * 2);
+        let pushed = count.get();
+        drop(apu);
+        assert_eq!(count.get(), pushed + pending);
+    }
+
+    #[test]
+    fn test_panning() {
+        let mut apu = powered_apu();
+        apu.write(0xFF24, 0x70); // Left at full volume, right at 1/8
+        apu.write(0xFF25, 0x12); // Channel 1 left, channel 2 right
+        let outputs = [Some(15), Some(15), None, None];
+        assert_eq!(apu.mix(&outputs), [-0.25, -0.25 / 8.0]);
+        apu.write(0xFF25, 0x00);
+        assert_eq!(apu.mix(&outputs), [0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_mute_solo() {
+        let mut apu = powered_apu();
+        apu.write(0xFF24, 0x77);
+        apu.write(0xFF25, 0xFF);
+        let outputs = [Some(15), Some(15), Some(0), None];
+        assert_eq!(apu.mix(&outputs), [-0.25, -0.25]);
+
+        apu.set_muted(0, true);
+        assert_eq!(apu.mix(&outputs), [0.0, 0.0]);
+        apu.set_solo(Some(2));
+        assert_eq!(apu.mix(&outputs), [0.25, 0.25]);
+        apu.set_solo(None);
+        apu.set_muted(0, false);
+        assert_eq!(apu.mix(&outputs), [-0.25, -0.25]);
+    }
+
+    #[test]
+    fn test_channel_info() {
+        let mut apu = powered_apu();
+        apu.write(0xFF16, 0x80 | 0x10); // 50% duty, length 0x10
+        apu.write(0xFF17, 0xA0);
+        apu.write(0xFF18, 0x00);
+        apu.write(0xFF19, 0xC7); // 2048 - 0x700 = 256, 512 Hz
+        let info = apu.channel_info(1);
+        assert!(info.enabled && info.length_enabled);
+        assert_eq!(info.frequency, 512.0);
+        assert_eq!(info.volume, 10);
+        assert_eq!(info.duty, Some(2));
+        assert_eq!(info.length, 48);
+        assert!(!apu.channel_info(2).enabled);
+
+        // NR32 volume codes: mute, 100%, 50% and 25%
+        for code in 0..4 {
+            apu.write(0xFF1C, code << 5);
+            assert_eq!(apu.channel_info(2).volume, code);
+        }
+
+        // Square wave in the history, muted or not
+        apu.set_muted(1, true);
+        for _ in 0..HISTORY_LEN * HISTORY_INTERVAL as usize {
+            apu.tick(0, false);
+        }
+        let history = apu.history(1);
+        assert_eq!(history.len(), HISTORY_LEN);
+        assert!(history.contains(&0) && history.contains(&10));
+        assert!(apu.history(0).iter().all(|out| *out == 0));
+    }
+}
@@ -0,0 +1,108 @@
+use log::warn;
+use std::fs::File;
+use std::io;
+use std::io::{ BufWriter, Seek, SeekFrom, Write };
+use std::path::Path;
+
+// Destination of the APU's output. Samples come in batches of interleaved
+// left and right values between -1.0 and 1.0.
+pub trait AudioSink {
+    fn push(&mut self, samples: &[f32]);
+
+    // Stereo frames waiting to be played by a realtime device, which lets
+    // the device drive emulation speed. None for sinks that take any amount.
+    fn queued(&self) -> Option<usize> {
+        None
+    }
+}
+
+// Throws the samples away
+pub struct NullSink;
+
+impl AudioSink for NullSink {
+    fn push(&mut self, _samples: &[f32]) {}
+}
+
+// Records 16-bit stereo PCM to a WAV file. The sizes in the header are
+// filled in when the writer is dropped.
+pub struct WavWriter {
+    file: BufWriter<File>,
+    data_len: u32
+}
+
+impl WavWriter {
+    pub fn create<P: AsRef<Path>>(path: P, sample_rate: u32) -> io::Result<WavWriter> {
+        let mut file = BufWriter::new(File::create(path)?);
+        let channels: u16 = 2;
+        let bits: u16 = 16;
+        let block_align = channels * bits / 8;
+
+        file.write_all(b"RIFF")?;
+        file.write_all(&36u32.to_le_bytes())?;
+        file.write_all(b"WAVE")?;
+        file.write_all(b"fmt ")?;
+        file.write_all(&16u32.to_le_bytes())?;
+        file.write_all(&1u16.to_le_bytes())?; // PCM
+        file.write_all(&channels.to_le_bytes())?;
+        file.write_all(&sample_rate.to_le_bytes())?;
+        file.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        file.write_all(&block_align.to_le_bytes())?;
+        file.write_all(&bits.to_le_bytes())?;
+        file.write_all(b"data")?;
+        file.write_all(&0u32.to_le_bytes())?;
+
+        Ok(WavWriter { file, data_len: 0 })
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file.write_all(&(36 + self.data_len).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(40))?;
+        self.file.write_all(&self.data_len.to_le_bytes())?;
+        self.file.flush()
+    }
+}
+
+impl AudioSink for WavWriter {
+    fn push(&mut self, samples: &[f32]) {
+        let pcm: Vec<u8> = samples.iter()
+            .flat_map(|x| ((x.clamp(-1.0, 1.0) * i16::MAX as f32) as i16).to_le_bytes())
+            .collect();
+        match self.file.write_all(&pcm) {
+            Ok(()) => self.data_len += pcm.len() as u32,
+            Err(why) => warn!("couldn't write audio: {}", why)
+        }
+    }
+}
+
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        if let Err(why) = self.finish() {
+            warn!("couldn't finish WAV file: {}", why);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_wav_writer() {
+        let path = std::env::temp_dir().join(format!("rustboy-test-{}.wav", std::process::id()));
+        {
+            let mut wav = WavWriter::create(&path, 48000).unwrap();
+            wav.push(&[0.0, 1.0, -1.0, 0.5]);
+        }
+
+        let data = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(data.len(), 44 + 8);
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(data[4..8].try_into().unwrap()), 44);
+        assert_eq!(u32::from_le_bytes(data[24..28].try_into().unwrap()), 48000);
+        assert_eq!(u32::from_le_bytes(data[40..44].try_into().unwrap()), 8);
+        assert_eq!(&data[44..], &[0x00, 0x00, 0xFF, 0x7F, 0x01, 0x80, 0xFF, 0x3F]);
+    }
+}
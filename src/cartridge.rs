@@ -0,0 +1,75 @@
+// Cartridge header at 0x0100-0x014F
+// https://gbdev.io/pandocs/The_Cartridge_Header.html
+#[derive(Debug, Clone, Copy)]
+pub struct Header {
+    pub title: [u8; 16],
+    pub cgb_flag: u8,
+    pub new_licensee: [u8; 2],
+    pub sgb_flag: u8,
+    pub cartridge_type: u8,
+    pub old_licensee: u8,
+    pub header_checksum: u8
+}
+
+impl Header {
+    pub fn parse(rom: &[u8]) -> Header {
+        let byte = |adr: usize| rom.get(adr).copied().unwrap_or(0);
+        let mut title = [0; 16];
+        title.iter_mut().enumerate().for_each(|(i, c)| *c = byte(0x0134 + i));
+
+        Header {
+            title,
+            cgb_flag: byte(0x0143),
+            new_licensee: [byte(0x0144), byte(0x0145)],
+            sgb_flag: byte(0x0146),
+            cartridge_type: byte(0x0147),
+            old_licensee: byte(0x014B),
+            header_checksum: byte(0x014D)
+        }
+    }
+
+    // Cartridge supports CGB functions, 0xC0 marks CGB only carts
+    pub fn supports_cgb(&self) -> bool {
+        self.cgb_flag & 0x80 != 0
+    }
+
+    // SGB functions are only enabled with the old licensee set to 0x33
+    pub fn supports_sgb(&self) -> bool {
+        self.sgb_flag == 0x03 && self.old_licensee == 0x33
+    }
+
+    pub fn is_nintendo(&self) -> bool {
+        match self.old_licensee {
+            0x01 => true,
+            0x33 => &self.new_licensee == b"01",
+            _ => false
+        }
+    }
+
+    // Sum of the title bytes, used by the CGB boot rom
+    pub fn title_checksum(&self) -> u8 {
+        self.title.iter().fold(0, |sum, c| sum.wrapping_add(*c))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_header() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0134..0x013A].copy_from_slice(b"TETRIS");
+        rom[0x0143] = 0x80;
+        rom[0x014B] = 0x01;
+        rom[0x014D] = 0x0A;
+
+        let header = Header::parse(&rom);
+        assert_eq!(&header.title[..7], b"TETRIS\0");
+        assert!(header.supports_cgb());
+        assert!(!header.supports_sgb());
+        assert!(header.is_nintendo());
+        assert_eq!(header.title_checksum(), 0x5B);
+        assert_eq!(header.header_checksum, 0x0A);
+    }
+}
@@ -3,71 +3,51 @@ use crate::mmu::*;
 use crate::cpu::*;
 
 impl Cpu {
+    // Operation in the upper five bits, register in the lower three
     pub fn cb_prefix(&mut self, opcode: u8, mem: &mut Memory) -> u16 {
+        let index = opcode & 0x07;
+        let bit = (opcode >> 3) & 0x07;
+        let n = self.read_r8(index, mem);
 
-        match opcode {
+        let val = match opcode >> 3 {
+            // RLC r
+            0x00 => alu_rlc(&mut self.reg, n),
 
-            // RLC (HL)
-            0x06 => {
-                let adr = self.reg.hl();
-                let val = alu_rlc(&mut self.reg, read_byte(adr, mem));
-                write_byte(adr, val, mem);
-                4
-            }
+            // RRC r
+            0x01 => alu_rrc(&mut self.reg, n),
 
-            // SLA A
-            0x27 => {
-                let n = self.reg.a;
-                let val = alu_sla(&mut self.reg, n);
-                self.reg.a = val;
-                2
-            }
-    
-            // SWAP A
-            0x37 => {
-                let n = self.reg.a;
-                self.reg.a = alu_swap(&mut self.reg, n);
-                2
-            },
+            // RL r
+            0x02 => alu_rl(&mut self.reg, n),
 
-            // BIT 0, C
-            0x41 => {
-                let r = self.reg.c;
-                alu_bit(&mut self.reg, 0, r);
-                2
-            },
+            // RR r
+            0x03 => alu_rr(&mut self.reg, n),
 
-            // BIT 5, A
-            0x6F => {
-                let b = self.reg.a;
-                alu_bit(&mut self.reg, b, 5);
-                2
-            },
+            // SLA r
+            0x04 => alu_sla(&mut self.reg, n),
 
-            // BIT 6, A
-            0x77 => {
-                let r = self.reg.a;
-                alu_bit(&mut self.reg, 6, r);
-                2
-            }
+            // SRA r
+            0x05 => alu_sra(&mut self.reg, n),
 
-            // RES 0, A
-            0x87 => {
-                self.reg.a &= 0xFE;
-                2
-            },
+            // SWAP r
+            0x06 => alu_swap(&mut self.reg, n),
 
-            // SET 7. (HL)
-            0xFE => {
-                let adr = self.reg.hl();
-                let val = read_byte(adr, mem) | 0x80;
-                write_byte(adr, val, mem);
-                4
+            // SRL r
+            0x07 => alu_srl(&mut self.reg, n),
+
+            // BIT b, r, only reads
+            0x08..=0x0F => {
+                alu_bit(&mut self.reg, bit, n);
+                return if index == 6 { 3 } else { 2 };
             },
-    
-            _ => {
-                panic!("unsupported instruction: CB {:#04x}", opcode);
-            }
-        }
+
+            // RES b, r
+            0x10..=0x17 => alu_res(n, bit),
+
+            // SET b, r
+            _ => alu_set(n, bit)
+        };
+
+        self.write_r8(index, val, mem);
+        if index == 6 { 4 } else { 2 }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,236 @@
+use rustboy::apu::DEFAULT_SAMPLE_RATE;
+use rustboy::compat_palette::*;
+use rustboy::model::*;
+
+use log::LevelFilter;
+use std::path::PathBuf;
+
+pub const USAGE: &str = "\
+Usage: rustboy [OPTIONS] ROM
+
+ROM is a cartridge image or a .gbs sound file.
+
+Options:
+  --model MODEL          dmg0, dmg, mgb, sgb, sgb2, cgb or agb, picked from the cartridge otherwise
+  --boot-rom PATH        run a boot rom before the cartridge
+  --scale N              window scale, 1, 2, 4 or 8
+  --speed FACTOR         emulation speed, 1 is real time and 0 unlimited, Tab toggles the limit
+  --headless             run without a window
+  --frames N             stop after N frames
+  --seconds N            stop after N emulated seconds, length of a GBS export
+  --log-level LEVEL      off, error, warn, info, debug or trace
+  --palette COMBO        colours for DMG games on CGB, e.g. up+a
+  --color-correction     approximate the colours of the CGB screen
+  --serial-stdout        print serial output as it's sent
+  --sample-rate HZ       audio output rate
+  --mute                 no sound, the window is paced by the clock instead
+  --wav PATH             record audio to a WAV file
+  --vgm PATH             log sound register writes to a VGM file
+  --track N              GBS track to play, from 1
+  --link                 second instance connected through a link cable
+  --ir                   second instance with the IR ports facing each other
+  --four-player          four instances connected through a DMG-07 adapter
+  --link-listen ADDR     wait for a link cable partner
+  --link-connect ADDR    connect to a link cable partner
+  --ir-udp LOCAL,PEER    IR port facing another process over UDP
+  -h, --help             print this help";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    pub rom: PathBuf,
+    pub model: Option<Model>,
+    pub boot_rom: Option<PathBuf>,
+    pub scale: usize,
+    // 0.0 for unlimited
+    pub speed: f64,
+    pub headless: bool,
+    pub frames: Option<u64>,
+    pub seconds: Option<f64>,
+    pub log_level: Option<LevelFilter>,
+    pub palette: Option<PaletteCombo>,
+    pub color_correction: bool,
+    pub serial_stdout: bool,
+    pub sample_rate: u32,
+    pub mute: bool,
+    pub wav: Option<PathBuf>,
+    pub vgm: Option<PathBuf>,
+    pub track: Option<u8>,
+    pub link: bool,
+    pub ir: bool,
+    pub four_player: bool,
+    pub link_listen: Option<String>,
+    pub link_connect: Option<String>,
+    pub ir_udp: Option<(String, String)>,
+    pub help: bool
+}
+
+impl Options {
+    fn new(rom: PathBuf) -> Options {
+        Options {
+            rom,
+            model: None,
+            boot_rom: None,
+            scale: 2,
+            speed: 1.0,
+            headless: false,
+            frames: None,
+            seconds: None,
+            log_level: None,
+            palette: None,
+            color_correction: false,
+            serial_stdout: false,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            mute: false,
+            wav: None,
+            vgm: None,
+            track: None,
+            link: false,
+            ir: false,
+            four_player: false,
+            link_listen: None,
+            link_connect: None,
+            ir_udp: None,
+            help: false
+        }
+    }
+
+    // Arguments without the program name
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Options, String> {
+        let mut options = Options::new(PathBuf::new());
+        let mut rom = None;
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+            match arg.as_str() {
+                "-h" | "--help" => options.help = true,
+                "--model" => options.model = Some(value()?.parse()?),
+                "--boot-rom" => options.boot_rom = Some(value()?.into()),
+                "--scale" => {
+                    options.scale = match value()?.as_str() {
+                        "1" => 1,
+                        "2" => 2,
+                        "4" => 4,
+                        "8" => 8,
+                        scale => return Err(format!("invalid scale '{}', expected 1, 2, 4 or 8", scale))
+                    }
+                },
+                "--speed" => {
+                    let speed = value()?;
+                    options.speed = speed.parse().ok()
+                        .filter(|speed: &f64| *speed >= 0.0 && speed.is_finite())
+                        .ok_or_else(|| format!("invalid speed '{}', expected a factor like 1 or 2.5", speed))?;
+                },
+                "--headless" => options.headless = true,
+                "--frames" => options.frames = Some(number(&arg, &value()?)?),
+                "--seconds" => {
+                    let seconds = value()?;
+                    options.seconds = Some(seconds.parse().ok()
+                        .filter(|seconds: &f64| *seconds > 0.0 && seconds.is_finite())
+                        .ok_or_else(|| format!("invalid length '{}', expected seconds", seconds))?);
+                },
+                "--log-level" => {
+                    let level = value()?;
+                    options.log_level = Some(level.parse()
+                        .map_err(|_| format!("invalid log level '{}', expected off, error, warn, info, debug or trace", level))?);
+                },
+                "--palette" => options.palette = Some(value()?.parse()?),
+                "--color-correction" => options.color_correction = true,
+                "--serial-stdout" => options.serial_stdout = true,
+                "--sample-rate" => {
+                    let rate = value()?;
+                    options.sample_rate = rate.parse().ok()
+                        .filter(|rate: &u32| *rate > 0)
+                        .ok_or_else(|| format!("invalid sample rate '{}', expected Hz like 48000", rate))?;
+                },
+                "--mute" => options.mute = true,
+                "--wav" => options.wav = Some(value()?.into()),
+                "--vgm" => options.vgm = Some(value()?.into()),
+                "--track" => options.track = Some(number(&arg, &value()?)?),
+                "--link" => options.link = true,
+                "--ir" => options.ir = true,
+                "--four-player" => options.four_player = true,
+                "--link-listen" => options.link_listen = Some(value()?),
+                "--link-connect" => options.link_connect = Some(value()?),
+                "--ir-udp" => {
+                    let addrs = value()?;
+                    let (local, peer) = addrs.split_once(',')
+                        .ok_or_else(|| format!("invalid --ir-udp '{}', expected LOCAL_ADDR,PEER_ADDR", addrs))?;
+                    options.ir_udp = Some((local.to_string(), peer.to_string()));
+                },
+                flag if flag.starts_with('-') => return Err(format!("unknown option '{}'", flag)),
+                path => match rom {
+                    None => rom = Some(PathBuf::from(path)),
+                    Some(_) => return Err(format!("unexpected argument '{}', only one ROM can be given", path))
+                }
+            }
+        }
+
+        if options.help {
+            return Ok(options);
+        }
+        options.rom = rom.ok_or("no ROM given")?;
+
+        let instances = [options.link, options.ir, options.four_player].iter().filter(|on| **on).count();
+        if options.four_player && instances > 1 {
+            return Err("--four-player can't be combined with --link or --ir".to_string());
+        }
+        if options.four_player && (options.link_listen.is_some() || options.link_connect.is_some()) {
+            return Err("--four-player can't be combined with --link-listen or --link-connect".to_string());
+        }
+        if options.link_listen.is_some() && options.link_connect.is_some() {
+            return Err("--link-listen and --link-connect exclude each other".to_string());
+        }
+        Ok(options)
+    }
+}
+
+fn number<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("invalid number '{}' for {}", value, flag))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Result<Options, String> {
+        Options::parse(args.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn test_parse() {
+        let options = parse("--model cgb tetris.gb --scale 4 --speed 0 --headless --frames 60 --log-level warn").unwrap();
+        assert_eq!(options.rom, PathBuf::from("tetris.gb"));
+        assert_eq!(options.model, Some(Model::Cgb));
+        assert_eq!(options.scale, 4);
+        assert_eq!(options.speed, 0.0);
+        assert!(options.headless);
+        assert_eq!(options.frames, Some(60));
+        assert_eq!(options.log_level, Some(LevelFilter::Warn));
+
+        let options = parse("game.gb --ir-udp 127.0.0.1:5000,127.0.0.1:5001").unwrap();
+        assert_eq!(options.speed, 1.0);
+        assert!(!options.mute);
+        assert!(parse("game.gb --mute").unwrap().mute);
+        assert_eq!(options.ir_udp, Some(("127.0.0.1:5000".to_string(), "127.0.0.1:5001".to_string())));
+        assert!(parse("--help").unwrap().help);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(parse("").unwrap_err(), "no ROM given");
+        assert_eq!(parse("a.gb b.gb").unwrap_err(), "unexpected argument 'b.gb', only one ROM can be given");
+        assert_eq!(parse("a.gb --frames").unwrap_err(), "--frames needs a value");
+        assert_eq!(parse("a.gb --frames many").unwrap_err(), "invalid number 'many' for --frames");
+        assert_eq!(parse("a.gb --fast").unwrap_err(), "unknown option '--fast'");
+        assert!(parse("a.gb --model gbc").unwrap_err().starts_with("unknown model 'gbc'"));
+        assert!(parse("a.gb --scale 3").is_err());
+        assert!(parse("a.gb --speed -1").is_err());
+        assert_eq!(parse("a.gb --sample-rate 0").unwrap_err(), "invalid sample rate '0', expected Hz like 48000");
+        assert!(parse("a.gb --sample-rate fast").is_err());
+        assert!(parse("a.gb --four-player --link").is_err());
+        assert_eq!(parse("a.gb --four-player --link-listen :5000").unwrap_err(),
+            "--four-player can't be combined with --link-listen or --link-connect");
+        assert!(parse("a.gb --link-connect host:5000 --four-player").is_err());
+    }
+}
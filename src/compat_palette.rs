@@ -0,0 +1,335 @@
+use crate::mmu::*;
+use crate::cartridge::*;
+
+use std::str::FromStr;
+
+// Colourisation of DMG games on CGB hardware.
+// The CGB boot rom picks a BG, OBJ0 and OBJ1 palette for games without CGB
+// support and loads them into palette RAM. The PPU then maps the BGP, OBP0
+// and OBP1 shades through them. Holding a direction plus A or B while the
+// boot logo shows overrides the choice.
+// https://gbdev.io/pandocs/Power_Up_Sequence.html#compatibility-palettes
+
+// Palette combinations selectable with button combos
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PaletteCombo {
+    Up,
+    UpA,
+    UpB,
+    Left,
+    LeftA,
+    LeftB,
+    Down,
+    DownA,
+    DownB,
+    Right,
+    RightA,
+    RightB
+}
+
+// BG, OBJ0 and OBJ1 colours as RGB888, lightest shade first
+type Palettes = [[u32; 4]; 3];
+
+const BROWN: [u32; 4] = [0xFFFFFF, 0xFFAD63, 0x843100, 0x000000];
+const RED: [u32; 4] = [0xFFFFFF, 0xFF8484, 0x943A3A, 0x000000];
+const GREEN: [u32; 4] = [0xFFFFFF, 0x7BFF31, 0x008400, 0x000000];
+const BLUE: [u32; 4] = [0xFFFFFF, 0x63A5FF, 0x0000FF, 0x000000];
+const DARK_BROWN: [u32; 4] = [0xFFE6C5, 0xCE9C84, 0x846B29, 0x5A3108];
+const DARK_BLUE: [u32; 4] = [0xFFFFFF, 0x8C8CDE, 0x52528C, 0x000000];
+const GRAY: [u32; 4] = [0xFFFFFF, 0xA5A5A5, 0x525252, 0x000000];
+const PASTEL: [u32; 4] = [0xFFFFA5, 0xFF9494, 0x9494FF, 0x000000];
+const ORANGE: [u32; 4] = [0xFFFFFF, 0xFFFF00, 0xFF0000, 0x000000];
+const YELLOW: [u32; 4] = [0xFFFFFF, 0xFFFF00, 0x7B4A00, 0x000000];
+const LIME: [u32; 4] = [0xFFFFFF, 0x52FF00, 0xFF4200, 0x000000];
+const DARK_GREEN: [u32; 4] = [0xFFFFFF, 0x7BFF31, 0x0063C5, 0x000000];
+const INVERTED: [u32; 4] = [0x000000, 0x008484, 0xFFDE00, 0xFFFFFF];
+const OLIVE: [u32; 4] = [0xFFFFFF, 0xADAD84, 0x42737B, 0x000000];
+const RUST: [u32; 4] = [0xFFFFFF, 0xFF7300, 0x944200, 0x000000];
+const GOLD: [u32; 4] = [0xFFC542, 0xFFD600, 0x943A00, 0x4A0000];
+const SKY: [u32; 4] = [0xFFFFFF, 0x5ABDFF, 0xFF0000, 0x0000FF];
+const WHITE_BLUE: [u32; 4] = [0xFFFFFF, 0xFFFFFF, 0x63A5FF, 0x0000FF];
+const LEAF: [u32; 4] = [0xFFFFFF, 0x00FF00, 0x318400, 0x004A00];
+
+// Colour sets of the boot rom, indexed by the low byte of a palette id
+const COMBINATIONS: [Palettes; 0x1D] = [
+    [OLIVE, RUST, SKY],
+    [[0xFFFF9C, 0x94B5FF, 0x639473, 0x003A3A], GOLD, SKY],
+    [[0x6BFF00, 0xFFFFFF, 0xFF524A, 0x000000], WHITE_BLUE, BROWN],
+    [[0x52DE00, 0xFF8400, 0xFFFF00, 0xFFFFFF], WHITE_BLUE, SKY],
+    [[0xFFFFFF, 0x7BFF00, 0xB57300, 0x000000], RED, SKY],
+    [LIME, RED, SKY],
+    [[0xFFFFFF, 0xFF9C00, 0xFF0000, 0x000000], RED, SKY],
+    [ORANGE, RED, SKY],
+    [[0xA59CFF, 0xFFFF00, 0x006300, 0x000000], [0xFF6352, 0xD60000, 0x630000, 0x000000], [0x0000FF, 0xFFFFFF, 0xFFFF7B, 0x0084FF]],
+    [[0xFFFFCE, 0x63EFEF, 0x9C8431, 0x5A5A5A], RUST, BLUE],
+    [[0xB5B5FF, 0xFFFF94, 0xAD5A42, 0x000000], [0x000000, 0xFFFFFF, 0xFF8484, 0x943A3A], [0x000000, 0xFFFFFF, 0xFF8484, 0x943A3A]],
+    [BLUE, RED, GREEN],
+    [DARK_BLUE, GOLD, SKY],
+    [DARK_BLUE, RED, BROWN],
+    [GREEN, RED, BLUE],
+    [RED, LEAF, BLUE],
+    [RED, GREEN, BLUE],
+    [LEAF, RED, BLUE],
+    [BROWN, BLUE, GREEN],
+    [INVERTED, INVERTED, INVERTED],
+    [BLUE, [0xFFFF00, 0xFF0000, 0x630000, 0x000000], GREEN],
+    [OLIVE, BROWN, BLUE],
+    [GRAY, GRAY, GRAY],
+    [PASTEL, PASTEL, PASTEL],
+    [BLUE, RED, GREEN],
+    [DARK_BROWN, BROWN, BROWN],
+    [YELLOW, BLUE, GREEN],
+    [[0xFFFFFF, 0xFFCE00, 0x9C6300, 0x000000]; 3],
+    [DARK_GREEN, RED, BLUE]
+];
+
+// Boot rom lookup by title checksum, Nintendo games only. Checksums shared by
+// several titles also match on the 4th title letter, a title whose letter has
+// no entry gets the default like one that isn't listed.
+const TITLE_PALETTES: [(u8, Option<u8>, u16); 89] = [
+    (0x01, None, 0x50F),       // DEFENDER/JOUST
+    (0x0C, None, 0x012),       // MANSELL
+    (0x0D, Some(b'E'), 0x30C), // POKEBOM
+    (0x0D, Some(b'R'), 0x407), // TETRIS2
+    (0x10, None, 0x50F),       // SUPER RC PRO-AM
+    (0x14, None, 0x110),       // POKEMON RED
+    (0x16, None, 0x012),       // YAKUMAN
+    (0x17, None, 0x50E),       // OTHELLO
+    (0x18, Some(b'I'), 0x31C), // WARIO BLAST
+    (0x18, Some(b'K'), 0x50C), // DONKEYKONGLAND
+    (0x19, None, 0x306),       // DONKEY KONG
+    (0x1D, None, 0x308),       // KIRBY'S PINBALL
+    (0x27, Some(b'B'), 0x508), // KIRBY BLOCKBALL
+    (0x27, Some(b'N'), 0x50E), // MAGNETIC SOCCER
+    (0x28, Some(b'A'), 0x013), // GALAGA&GALAXIAN
+    (0x28, Some(b'F'), 0x30E), // GOLF
+    (0x29, None, 0x50F),       // MEGAMAN3
+    (0x34, None, 0x304),       // GAMEBOY GALLERY
+    (0x35, None, 0x012),       // MARIO'S PICROSS
+    (0x36, None, 0x503),       // BASEBALL
+    (0x39, None, 0x30F),       // DYNABLASTER
+    (0x3C, None, 0x20B),       // DR.MARIO
+    (0x3D, None, 0x305),       // YOSSY NO TAMAGO
+    (0x3E, None, 0x406),       // YOSSY NO COOKIE
+    (0x3F, None, 0x31C),       // TETRIS PLUS
+    (0x43, None, 0x30F),       // THE CHESSMASTER
+    (0x46, Some(b'E'), 0x30A), // SUPER MARIOLAND
+    (0x46, Some(b'R'), 0x514), // METROID2
+    (0x49, None, 0x508),       // KIRBY DREAM LAND
+    (0x4B, None, 0x30E),       // DMG FOOTBALL
+    (0x4E, None, 0x50B),       // WAVERACE
+    (0x52, None, 0x50F),       // STREET FIGHTER 2
+    (0x58, None, 0x016),       // X
+    (0x59, None, 0x500),       // SUPERMARIOLAND3
+    (0x5C, None, 0x508),       // HOSHINOKA-BI
+    (0x5D, None, 0x50F),       // BA.TOSHINDEN
+    (0x61, Some(b'A'), 0x50E), // VEGAS STAKES
+    (0x61, Some(b'E'), 0x10B), // POKEMON BLUE
+    (0x66, Some(b'E'), 0x304), // GAMEBOY GALLERY2
+    (0x66, Some(b'L'), 0x31C), // MILLI/CENTI/PEDE
+    (0x67, None, 0x012),       // STAR STACKER
+    (0x68, None, 0x50F),       // LOLO2
+    (0x69, None, 0x407),       // TETRIS FLASH
+    (0x6A, Some(b'I'), 0x305), // MARIO & YOSHI
+    (0x6A, Some(b'K'), 0x50C), // DONKEYKONGLAND 2
+    (0x6B, None, 0x50C),       // DONKEYKONGLAND 3
+    (0x6D, None, 0x50F),       // NETTOU KOF 95
+    (0x70, None, 0x511),       // ZELDA
+    (0x71, None, 0x006),       // TETRIS BLAST
+    (0x75, None, 0x012),       // PICROSS 2
+    (0x86, None, 0x501),       // DONKEYKONGLAND95
+    (0x88, None, 0x008),       // ALLEY WAY
+    (0x8B, None, 0x50E),       // MYSTIC QUEST
+    (0x8C, None, 0x100),       // RADARMISSION
+    (0x90, None, 0x30E),       // WORLD CUP
+    (0x92, None, 0x012),       // F1RACE
+    (0x95, None, 0x405),       // YOSSY NO PANEPON
+    (0x97, None, 0x30F),       // KINGOFTHEZOO
+    (0x99, None, 0x012),       // KIRAKIRA KIDS
+    (0x9A, None, 0x30E),       // ASTEROIDS/MISCMD
+    (0x9C, None, 0x20C),       // PINOCCHIO
+    (0x9D, None, 0x50D),       // KILLERINSTINCT95
+    (0xA2, None, 0x512),       // STAR WARS-NOA
+    (0xA5, Some(b'A'), 0x013), // SOLARSTRIKER
+    (0xA5, Some(b'R'), 0x312), // BT2RAGNAROKWORLD
+    (0xAA, None, 0x11C),       // POKEMON GREEN
+    (0xB3, Some(b'B'), 0x508), // KIRBY2
+    (0xB3, Some(b'R'), 0x405), // TETRIS ATTACK
+    (0xB3, Some(b'U'), 0x300), // MOGURANYA
+    (0xB7, None, 0x012),       // GAME&WATCH
+    (0xBD, None, 0x30E),       // TOY STORY
+    (0xBF, Some(b' '), 0x30D), // KID ICARUS
+    (0xBF, Some(b'C'), 0x502), // SOCCER
+    (0xC6, Some(b' '), 0x31C), // KEN GRIFFEY JR
+    (0xC6, Some(b'A'), 0x500), // GBWARS
+    (0xC9, None, 0x509),       // MARIOLAND2
+    (0xCE, None, 0x502),       // TOPRANKINGTENNIS
+    (0xD1, None, 0x502),       // TENNIS
+    (0xD3, Some(b'R'), 0x10D), // KAERUNOTAMENI
+    (0xDB, None, 0x007),       // TETRIS
+    (0xE0, None, 0x406),       // YOSHI'S COOKIE
+    (0xE8, None, 0x013),       // SPACE INVADERS
+    (0xF0, None, 0x502),       // TOPRANKTENNIS
+    (0xF2, None, 0x407),       // QIX
+    (0xF4, Some(b' '), 0x304), // G&W GALLERY
+    (0xF4, Some(b'-'), 0x51C), // PAC-IN-TIME
+    (0xF6, None, 0x50F),       // MEGAMAN
+    (0xF7, None, 0x512),       // BOY AND BLOB GB2
+    (0xFF, None, 0x006),       // BALLOON KID
+];
+
+// A palette as the boot rom numbers it, 0xFCC: combination CC, with flags F
+// choosing which of its colours the OBJ palettes get. Bit 0 gives OBJ0 its
+// own, bit 1 gives OBJ1 the OBJ0 colours and bit 2 its own. Without flags
+// both use the BG colours.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompatPalette(pub u16);
+
+impl CompatPalette {
+    // Used when the title is not in the table or not made by Nintendo
+    pub const DEFAULT: CompatPalette = CompatPalette(0x31C);
+
+    // Palette the boot rom picks for a cartridge
+    pub fn detect(header: &Header) -> CompatPalette {
+        if !header.is_nintendo() {
+            return CompatPalette::DEFAULT;
+        }
+
+        let checksum = header.title_checksum();
+        TITLE_PALETTES.iter()
+            .find(|(sum, letter, _)| *sum == checksum && letter.is_none_or(|c| c == header.title[3]))
+            .map_or(CompatPalette::DEFAULT, |(_, _, id)| CompatPalette(*id))
+    }
+
+    fn palettes(&self) -> Palettes {
+        let [bg, obj0, obj1] = COMBINATIONS[(self.0 & 0xFF) as usize];
+        let flags = self.0 >> 8;
+        let first = if flags & 0x01 != 0 { obj0 } else { bg };
+        let second = if flags & 0x04 != 0 {
+            obj1
+        } else if flags & 0x02 != 0 {
+            obj0
+        } else {
+            bg
+        };
+        [bg, first, second]
+    }
+}
+
+impl PaletteCombo {
+    pub fn palette(&self) -> CompatPalette {
+        CompatPalette(match self {
+            PaletteCombo::Up => 0x012,
+            PaletteCombo::UpA => 0x510,
+            PaletteCombo::UpB => 0x319,
+            PaletteCombo::Left => 0x518,
+            PaletteCombo::LeftA => 0x50D,
+            PaletteCombo::LeftB => 0x016,
+            PaletteCombo::Down => 0x017,
+            PaletteCombo::DownA => 0x007,
+            PaletteCombo::DownB => 0x51A,
+            PaletteCombo::Right => 0x005,
+            PaletteCombo::RightA => 0x31C,
+            PaletteCombo::RightB => 0x013
+        })
+    }
+}
+
+impl FromStr for PaletteCombo {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<PaletteCombo, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "up" => Ok(PaletteCombo::Up),
+            "up+a" => Ok(PaletteCombo::UpA),
+            "up+b" => Ok(PaletteCombo::UpB),
+            "left" => Ok(PaletteCombo::Left),
+            "left+a" => Ok(PaletteCombo::LeftA),
+            "left+b" => Ok(PaletteCombo::LeftB),
+            "down" => Ok(PaletteCombo::Down),
+            "down+a" => Ok(PaletteCombo::DownA),
+            "down+b" => Ok(PaletteCombo::DownB),
+            "right" => Ok(PaletteCombo::Right),
+            "right+a" => Ok(PaletteCombo::RightA),
+            "right+b" => Ok(PaletteCombo::RightB),
+            _ => Err(format!("unknown palette '{}', expected a direction optionally followed by +a or +b", s))
+        }
+    }
+}
+
+// Load the combination into palette RAM like the boot rom does:
+// BG palette 0, OBJ palettes 0 and 1
+pub fn load_compat_palette(mem: &mut Memory, palette: CompatPalette) {
+    let [bg, obj0, obj1] = palette.palettes();
+    store_palette(&mut mem.bg_palette, 0, &bg);
+    store_palette(&mut mem.obj_palette, 0, &obj0);
+    store_palette(&mut mem.obj_palette, 1, &obj1);
+}
+
+fn store_palette(ram: &mut [u8; 64], palette: usize, colors: &[u32; 4]) {
+    for (i, rgb) in colors.iter().enumerate() {
+        let color = rgb888_to_rgb555(*rgb);
+        ram[palette * 8 + i * 2] = color as u8;
+        ram[palette * 8 + i * 2 + 1] = (color >> 8) as u8;
+    }
+}
+
+fn rgb888_to_rgb555(rgb: u32) -> u16 {
+    let r = (rgb >> 19) & 0x1F;
+    let g = (rgb >> 11) & 0x1F;
+    let b = (rgb >> 3) & 0x1F;
+    (r | g << 5 | b << 10) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpu::palette_color;
+
+    fn header(title: &[u8], old_licensee: u8) -> Header {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0134..0x0134 + title.len()].copy_from_slice(title);
+        rom[0x014B] = old_licensee;
+        Header::parse(&rom)
+    }
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(CompatPalette::detect(&header(b"TETRIS", 0x01)), CompatPalette(0x007));
+        assert_eq!(CompatPalette::detect(&header(b"POKEMON RED", 0x01)), CompatPalette(0x110));
+        // Only Nintendo titles are looked up
+        assert_eq!(CompatPalette::detect(&header(b"POKEMON RED", 0x08)), CompatPalette::DEFAULT);
+        assert_eq!(CompatPalette::detect(&header(b"UNKNOWN", 0x01)), CompatPalette::DEFAULT);
+        assert_eq!("Left+B".parse::<PaletteCombo>(), Ok(PaletteCombo::LeftB));
+        assert!("sideways".parse::<PaletteCombo>().is_err());
+    }
+
+    #[test]
+    fn test_detect_shared_checksum() {
+        // Both sum to 0x61, the 4th letter tells them apart
+        let blue = header(b"POKEMON BLUE", 0x01);
+        let vegas = header(b"VEGAS STAKES", 0x01);
+        assert_eq!(blue.title_checksum(), vegas.title_checksum());
+        assert_eq!(CompatPalette::detect(&blue), CompatPalette(0x10B));
+        assert_eq!(CompatPalette::detect(&vegas), CompatPalette(0x50E));
+        // A letter not in the table falls back to the default
+        assert_eq!(CompatPalette::detect(&header(b"ROCKMAN WORLD", 0x01)), CompatPalette::DEFAULT);
+    }
+
+    #[test]
+    fn test_load_compat_palette() {
+        let mut mem = Memory::new();
+        load_compat_palette(&mut mem, PaletteCombo::RightA.palette());
+        assert_eq!(palette_color(&mem.bg_palette, 0, 0), 0x7FFF);
+        assert_eq!(palette_color(&mem.bg_palette, 0, 2), 0x6180);
+        assert_eq!(palette_color(&mem.obj_palette, 1, 3), 0x0000);
+
+        // OBJ0 gets the BG colours, OBJ1 the combination's OBJ0 colours
+        load_compat_palette(&mut mem, CompatPalette(0x20B));
+        let [_, obj0, _] = COMBINATIONS[0x0B];
+        for (i, rgb) in (0..4).zip(obj0) {
+            assert_eq!(palette_color(&mem.obj_palette, 0, i), palette_color(&mem.bg_palette, 0, i));
+            assert_eq!(palette_color(&mem.obj_palette, 1, i), rgb888_to_rgb555(rgb));
+        }
+    }
+}
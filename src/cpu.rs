@@ -2,7 +2,7 @@ use crate::alu::*;
 use crate::registers::*;
 use crate::mmu::*;
 
-use log::debug;
+use log::{ debug, warn };
 
 trait SignedAdd {
     fn signed_add(self, rhs: i8) -> Self;
@@ -18,7 +18,12 @@ pub struct Cpu {
     pub reg: Registers,
     ime_delay: u8,
     ime_set: Option<bool>,
-    t_states: u32
+    t_states: u32,
+    // Waiting for an interrupt after HALT
+    pub halted: bool,
+    // Address and opcode of an illegal instruction the CPU hung on, like the
+    // real one it stays hung until reset
+    pub lockup: Option<(u16, u8)>
 }
 
 impl Cpu {
@@ -27,16 +32,23 @@ impl Cpu {
             reg: Registers::new(),
             ime_delay: 0,
             ime_set: None,
-            t_states: 0
+            t_states: 0,
+            halted: false,
+            lockup: None
         }
     }
 
     // Returns tick length in m-cycles
     pub fn tick(&mut self, mem: &mut Memory) -> u16 {
-        let tick_len = match self.interrupt(mem) {
-            Some(cycles) => cycles,
-            None => {
-                self.call_instruction(mem)
+        let tick_len = if self.lockup.is_some() {
+            1
+        } else {
+            match self.interrupt(mem) {
+                Some(cycles) => cycles,
+                None if self.halted => 1,
+                None => {
+                    self.call_instruction(mem)
+                }
             }
         };
         self.t_states += (tick_len * 4) as u32;
@@ -49,6 +61,15 @@ impl Cpu {
         tick_len
     }
 
+    // A hung CPU as an error, for frontends to stop on instead of showing
+    // a frozen screen
+    pub fn check(&self) -> Result<(), String> {
+        match self.lockup {
+            Some((adr, opcode)) => Err(format!("CPU hung on illegal opcode {:#04x} at {:#06x}", opcode, adr)),
+            None => Ok(())
+        }
+    }
+
     fn interrupt(&mut self, mem: &mut Memory) -> Option<u16> {
         let if_flag = mem[0xFF0F];
         let ie_flag = mem[0xFFFF];
@@ -71,48 +92,49 @@ impl Cpu {
             _ => 0,
         };
 
-        if self.reg.ime && if_flag & ie_flag != 0 {
+        let pending = if_flag & ie_flag & 0x1F;
+        // HALT ends on any pending interrupt, enabled or not
+        if pending != 0 {
+            self.halted = false;
+        }
+        if self.reg.ime && pending != 0 {
+            // Lowest bit has the highest priority:
+            // VBlank, LCD STAT, Timer, Serial, Joypad
+            let bit = pending.trailing_zeros() as u16;
             self.reg.ime = false;
-            match if_flag { // check if an interrupt is pending
-                0x00 => {}, // no interrupt pending
-                0x01 => { // VBlank interrupt
-                    self.push_stack(self.reg.pc, mem);
-                    self.reg.pc = 0x0040;
-                    debug!("VBlank interrupt!");
-                    return Some(4);
-                },
-                _ => {
-                    panic!("Unhandled interrupt flag: 0x{:X}", mem[0xFF0F]);
-                }
-            }
+            mem[0xFF0F] &= !(1 << bit);
+            self.push_stack(self.reg.pc, mem);
+            self.reg.pc = 0x0040 + bit * 8;
+            debug!("Interrupt! Jumping to {:#06x}", self.reg.pc);
+            return Some(4);
         }
 
         return None;
     }
 
     // Get next byte from memory and increment program counter
-    fn next_byte(&mut self, mem: &mut [u8]) -> u8 {
+    fn next_byte(&mut self, mem: &Memory) -> u8 {
         let byte = read_byte(self.reg.pc, mem);
-        self.reg.pc += 1;
+        self.reg.pc = self.reg.pc.wrapping_add(1);
         byte
     }
     
     // Get next word from memory and increment program counter
-    fn next_word(&mut self, mem: &[u8]) -> u16 {
+    fn next_word(&mut self, mem: &Memory) -> u16 {
         let word = read_word(self.reg.pc, mem);
-        self.reg.pc += 2;
+        self.reg.pc = self.reg.pc.wrapping_add(2);
         word
     }
     
     fn push_stack(&mut self, val: u16, mem: &mut Memory) {
-        self.reg.sp -= 2;
+        self.reg.sp = self.reg.sp.wrapping_sub(2);
         write_word(self.reg.sp, val, mem);
         debug!("PUSH: {:#04x}", val);
     }
     
     fn pop_stack(&mut self, mem: &mut Memory) -> u16 {
         let val = read_word(self.reg.sp, mem);
-        self.reg.sp += 2;
+        self.reg.sp = self.reg.sp.wrapping_add(2);
         debug!("POP: {:#04x}", val);
         val
     }
@@ -122,6 +144,85 @@ impl Cpu {
         self.ime_delay = 2;
     }
 
+    // Register by its index in an opcode: B, C, D, E, H, L, (HL), A
+    pub(crate) fn read_r8(&self, index: u8, mem: &Memory) -> u8 {
+        match index {
+            0 => self.reg.b,
+            1 => self.reg.c,
+            2 => self.reg.d,
+            3 => self.reg.e,
+            4 => self.reg.h,
+            5 => self.reg.l,
+            6 => read_byte(self.reg.hl(), mem),
+            _ => self.reg.a
+        }
+    }
+
+    pub(crate) fn write_r8(&mut self, index: u8, val: u8, mem: &mut Memory) {
+        match index {
+            0 => self.reg.b = val,
+            1 => self.reg.c = val,
+            2 => self.reg.d = val,
+            3 => self.reg.e = val,
+            4 => self.reg.h = val,
+            5 => self.reg.l = val,
+            6 => write_byte(self.reg.hl(), val, mem),
+            _ => self.reg.a = val
+        }
+    }
+
+    // Condition by its index in an opcode: NZ, Z, NC, C
+    fn condition(&self, index: u8) -> bool {
+        match index & 0x03 {
+            0 => !self.reg.get_flag(Flag::Z),
+            1 => self.reg.get_flag(Flag::Z),
+            2 => !self.reg.get_flag(Flag::C),
+            _ => self.reg.get_flag(Flag::C)
+        }
+    }
+
+    fn jr(&mut self, taken: bool, mem: &Memory) -> u16 {
+        let n = self.next_byte(mem) as i8;
+        if taken {
+            self.reg.pc = self.reg.pc.signed_add(n);
+            return 3;
+        }
+        2
+    }
+
+    fn jp(&mut self, taken: bool, mem: &Memory) -> u16 {
+        let adr = self.next_word(mem);
+        if taken {
+            self.reg.pc = adr;
+            return 4;
+        }
+        3
+    }
+
+    fn call(&mut self, taken: bool, mem: &mut Memory) -> u16 {
+        let adr = self.next_word(mem);
+        if taken {
+            self.push_stack(self.reg.pc, mem);
+            self.reg.pc = adr;
+            return 6;
+        }
+        3
+    }
+
+    fn ret(&mut self, taken: bool, mem: &mut Memory) -> u16 {
+        if taken {
+            self.reg.pc = self.pop_stack(mem);
+            return 5;
+        }
+        2
+    }
+
+    fn rst(&mut self, adr: u16, mem: &mut Memory) -> u16 {
+        self.push_stack(self.reg.pc, mem);
+        self.reg.pc = adr;
+        4
+    }
+
     // Cpu instruction set
     // Returns m-cycle length of instruction
     fn call_instruction(&mut self, mem: &mut Memory) -> u16 {
@@ -151,24 +252,29 @@ impl Cpu {
                 self.reg.set_bc(self.reg.bc().wrapping_add(1));
                 2
             },
-    
-            // INC B
-            0x04 => {
-                let n = self.reg.b;
-                self.reg.b = alu_inc(&mut self.reg, n);
+
+            // INC r
+            0x04 | 0x0C | 0x14 | 0x1C | 0x24 | 0x2C | 0x3C => {
+                let index = opcode >> 3;
+                let n = self.read_r8(index, mem);
+                let val = alu_inc(&mut self.reg, n);
+                self.write_r8(index, val, mem);
                 1
             },
-    
-            // DEC B
-            0x05 => {
-                let n = self.reg.b;
-                self.reg.b = alu_dec(&mut self.reg, n);
+
+            // DEC r
+            0x05 | 0x0D | 0x15 | 0x1D | 0x25 | 0x2D | 0x3D => {
+                let index = opcode >> 3;
+                let n = self.read_r8(index, mem);
+                let val = alu_dec(&mut self.reg, n);
+                self.write_r8(index, val, mem);
                 1
             },
-    
-            // LD B, d8
-            0x06 => {
-                self.reg.b = self.next_byte(mem);
+
+            // LD r, d8
+            0x06 | 0x0E | 0x16 | 0x1E | 0x26 | 0x2E | 0x3E => {
+                let val = self.next_byte(mem);
+                self.write_r8(opcode >> 3, val, mem);
                 2
             },
     
@@ -208,22 +314,24 @@ impl Cpu {
                 self.reg.set_bc(self.reg.bc().wrapping_sub(1));
                 2
             },
-    
-            // DEC C
-            0x0D => {
-                let n = self.reg.c;
-                self.reg.c = alu_dec(&mut self.reg, n);
+
+            // RRCA
+            0x0F => {
+                let n = self.reg.a;
+                self.reg.a = alu_rrc(&mut self.reg, n);
+                self.reg.set_flag(Flag::Z, false);
                 1
             },
     
-            // LD C, d8
-            0x0E => {
-                self.reg.c = self.next_byte(mem);
-                2
-            },
-    
             // STOP
-            0x10 => { 1 },
+            0x10 => {
+                // Followed by a padding byte
+                self.next_byte(mem);
+                if switch_speed(mem) {
+                    debug!("Double speed: {}", mem.double_speed);
+                }
+                1
+            },
     
             // LD DE, d16
             0x11 => {
@@ -239,46 +347,23 @@ impl Cpu {
                 write_byte(adr, val, mem);
                 2
             },
-    
-            // INC D
-            0x14 => {
-                let n = self.reg.d;
-                self.reg.d = alu_inc(&mut self.reg, n);
-                1
-            }
-    
-            // DEC D
-            0x15 => {
-                let n = self.reg.d;
-                self.reg.d = alu_dec(&mut self.reg, n);
-                1
-            },
-    
-            // LD D, d8
-            0x16 => {
-                self.reg.d = self.next_byte(mem);
-                2
-            },
-    
-            // JR r8
-            0x18 => {
-                let n = self.next_byte(mem) as i8;
-                self.reg.pc = ((self.reg.pc as i32) + (n as i32)) as u16;
-                3
-            },
-    
-            // INC C
-            0x0C => {
-                let n = self.reg.c;
-                self.reg.c = alu_inc(&mut self.reg, n);
-                1
-            },
 
             // INC DE
             0x13 => {
                 self.reg.set_de(self.reg.de().wrapping_add(1));
                 2
             },
+
+            // RLA
+            0x17 => {
+                let n = self.reg.a;
+                self.reg.a = alu_rl(&mut self.reg, n);
+                self.reg.set_flag(Flag::Z, false);
+                1
+            },
+    
+            // JR r8
+            0x18 => self.jr(true, mem),
     
             // ADD HL, DE
             0x19 => {
@@ -299,26 +384,6 @@ impl Cpu {
                 self.reg.set_de(self.reg.de().wrapping_sub(1));
                 2
             },
-
-            // INC E
-            0x1C => {
-                let n = self.reg.e;
-                self.reg.e = alu_inc(&mut self.reg, n);
-                1
-            },
-    
-            // DEC E
-            0x1D => {
-                let n = self.reg.e;
-                self.reg.e = alu_dec(&mut self.reg, n);
-                1
-            },
-    
-            // LD E, d8
-            0x1E => {
-                self.reg.e = self.next_byte(mem);
-                2
-            },
     
             // RRA
             0x1F => {
@@ -331,16 +396,10 @@ impl Cpu {
                 1
             },
     
-            // JR NZ, r8
-            0x20 => {
-                if !self.reg.get_flag(Flag::Z) {
-                    let n = self.next_byte(mem) as i8;
-                    self.reg.pc = self.reg.pc.signed_add(n);
-                    return 3;
-                } else {
-                    self.reg.pc += 1;
-                    2
-                }
+            // JR cc, r8
+            0x20 | 0x28 | 0x30 | 0x38 => {
+                let taken = self.condition(opcode >> 3);
+                self.jr(taken, mem)
             },
     
             // LD HL, d16
@@ -363,24 +422,18 @@ impl Cpu {
                 self.reg.set_hl(self.reg.hl().wrapping_add(1));
                 2
             },
-    
-            // DEC H
-            0x25 => {
-                let n = self.reg.h;
-                self.reg.h = alu_dec(&mut self.reg, n);
+
+            // DAA
+            0x27 => {
+                alu_daa(&mut self.reg);
                 1
             },
-
-            // JR Z, r8
-            0x28 => {
-                if self.reg.get_flag(Flag::Z) {
-                    let n = self.next_byte(mem) as i8;
-                    self.reg.pc = self.reg.pc.signed_add(n);
-                    return 3;
-                } else {
-                    self.reg.pc += 1;
-                    2
-                }
+    
+            // ADD HL, HL
+            0x29 => {
+                let n = self.reg.hl();
+                alu_add_hl(&mut self.reg, n);
+                2
             },
     
             // LD A, (HL+)
@@ -396,27 +449,6 @@ impl Cpu {
                 2
             },
     
-            // INC L
-            0x2C => {
-                let n = self.reg.l;
-                self.reg.l = alu_inc(&mut self.reg, n);
-                1
-            },
-
-            // DEC L
-            0x2D => {
-                let n = self.reg.l;
-                self.reg.l = alu_dec(&mut self.reg, n);
-                1
-            },
-    
-            // ADD HL, HL
-            0x29 => {
-                let n = self.reg.hl();
-                alu_add_hl(&mut self.reg, n);
-                2
-            },
-    
             // CPL
             0x2F => {
                 self.reg.a = !self.reg.a;
@@ -438,335 +470,110 @@ impl Cpu {
                 2
             },
 
+            // INC SP
+            0x33 => {
+                self.reg.sp = self.reg.sp.wrapping_add(1);
+                2
+            },
+
             // INC (HL)
             0x34 => {
                 let adr = self.reg.hl();
-                let val = read_byte(adr, mem);
-                write_byte(adr + 0x0001, val, mem);
+                let val = alu_inc(&mut self.reg, read_byte(adr, mem));
+                write_byte(adr, val, mem);
                 3
             },
-    
-            //LD (HL), d8
-            0x36 => {
-                let n = self.next_byte(mem);
-                alu_inc(&mut self.reg, n);
-                2
-            },
-    
-            // JR C, r8
-            0x38 => {
-                if self.reg.get_flag(Flag::C) {
-                    let n = self.next_byte(mem) as i8;
-                    self.reg.pc = ((self.reg.pc as i32) + (n as i32)) as u16;
-                }
-                2
-            },
-
-            // LD A, (HL-)
-            0x3A => {
-                self.reg.a = read_byte(self.reg.hl(), mem);
-                self.reg.set_hl(self.reg.hl().wrapping_sub(1));
-                2
-            },
 
-            // INC A
-            0x3C => {
-                let n = self.reg.a;
-                self.reg.a = alu_inc(&mut self.reg, n);
-                1
-            },
-
-            // DEC A
-            0x3D => {
-                let n = self.reg.a;
-                self.reg.a = alu_dec(&mut self.reg, n);
-                1
-            },
-    
-            // LD A, d8
-            0x3E => {
-                self.reg.a = self.next_byte(mem);
-                2
+            // DEC (HL)
+            0x35 => {
+                let adr = self.reg.hl();
+                let val = alu_dec(&mut self.reg, read_byte(adr, mem));
+                write_byte(adr, val, mem);
+                3
             },
     
-            // LD B, C
-            0x41 => {
-                self.reg.b = self.reg.c;
-                1
+            // LD (HL), d8
+            0x36 => {
+                let n = self.next_byte(mem);
+                write_byte(self.reg.hl(), n, mem);
+                3
             },
 
-            // LD B, (HL)
-            0x46 => {
-                self.reg.b = read_byte(self.reg.hl(), mem);
-                2
-            },
-    
-            // LD B, A
-            0x47 => {
-                self.reg.b = self.reg.a;
+            // SCF
+            0x37 => {
+                self.reg.set_flag(Flag::N, false);
+                self.reg.set_flag(Flag::H, false);
+                self.reg.set_flag(Flag::C, true);
                 1
             },
 
-            // LD C, C
-            0x49 => { 1 },
-
-            // LD C, (HL)
-            0x4E => {
-                self.reg.c = read_byte(self.reg.hl(), mem);
+            // ADD HL, SP
+            0x39 => {
+                let n = self.reg.sp;
+                alu_add_hl(&mut self.reg, n);
                 2
             },
-    
-            // LD C, A
-            0x4F => {
-                self.reg.c = self.reg.a;
-                1
-            },
 
-            // LD D, (HL)
-            0x56 => {
-                self.reg.d = read_byte(self.reg.hl(), mem);
+            // LD A, (HL-)
+            0x3A => {
+                self.reg.a = read_byte(self.reg.hl(), mem);
+                self.reg.set_hl(self.reg.hl().wrapping_sub(1));
                 2
             },
 
-            // LD D, A
-            0x57 => {
-                self.reg.d = self.reg.a;
-                1
-            },
-
-            // LD E, (HL)
-            0x5E => { 
-                self.reg.e = read_byte(self.reg.hl(), mem);
+            // DEC SP
+            0x3B => {
+                self.reg.sp = self.reg.sp.wrapping_sub(1);
                 2
             },
 
-            // LD E, A
-            0x5F => {
-                self.reg.e = self.reg.a;
-                1
-            },
-
-            // LD H, B
-            0x60 => {
-                self.reg.h = self.reg.b;
-                1
-            },
-    
-            // LD H, A
-            0x67 => {
-                self.reg.h = self.reg.a;
-                1
-            },
-
-            // LD L, C
-            0x69 => {
-                self.reg.l = self.reg.c;
-                1
-            },
-
-            // LD L, A
-            0x6F => {
-                self.reg.l = self.reg.a;
-                1
-            },
-    
-            // LD (HL), A
-            0x77 => {
-                write_byte(self.reg.hl(), self.reg.a, mem);
-                2
-            },
-    
-            // LD A, B
-            0x78 => {
-                self.reg.a = self.reg.b;
-                1
-            },
-    
-            // LD A, C
-            0x79 => {
-                self.reg.a = self.reg.c;
-                1
-            },
-
-            // LD A, D
-            0x7A => {
-                self.reg.a = self.reg.d;
-                1
-            },
-    
-            // LD A, E
-            0x7B => {
-                self.reg.a = self.reg.e;
-                1
-            },
-    
-            // LD A, H
-            0x7C => {
-                self.reg.a = self.reg.h;
-                1
-            },
-
-            // LD A, L
-            0x7D => {
-                self.reg.a = self.reg.l;
-                1
-            },
-
-            // LD A, (HL)
-            0x7E => {
-                let adr = self.reg.hl();
-                self.reg.a = read_byte(adr, mem);
-                2
-            },
-    
-            // LD A, A
-            0x7F => {
-                self.reg.a = self.reg.a;
-                1
-            },
-
-            // ADD A, B
-            0x80 => {
-                let n = self.reg.b;
-                alu_add(&mut self.reg, n);
-                1
-            },
-
-            // ADD A, D
-            0x82 => {
-                let n = self.reg.d;
-                alu_add(&mut self.reg, n);
-                1
-            },
-
-            // ADD A, L
-            0x85 => {
-                let n = self.reg.l;
-                alu_add(&mut self.reg, n);
-                1
-            },
-
-            // ADD A, A
-            0x87 => {
-                let n = self.reg.a;
-                alu_add(&mut self.reg, n);
-                1
-            },
-
-            // ADC A, C
-            0x89 => {
-                let n = self.reg.c;
-                alu_adc(&mut self.reg, n);
-                1
-            },
-    
-            // ADD A, D
-            0x8A => {
-                let n = self.reg.d;
-                alu_add(&mut self.reg, n);
-                1
-            },
-
-            // SUB B
-            0x90 => {
-                let n = self.reg.b;
-                alu_sub(&mut self.reg, n);
-                1
-            },
-    
-            // SUB E
-            0x93 => {
-                let n = self.reg.e;
-                alu_sub(&mut self.reg, n);
-                1
-            },
-
-            // SUB H
-            0x94 => {
-                let n = self.reg.h;
-                alu_sub(&mut self.reg, n);
+            // CCF
+            0x3F => {
+                let c = self.reg.get_flag(Flag::C);
+                self.reg.set_flag(Flag::N, false);
+                self.reg.set_flag(Flag::H, false);
+                self.reg.set_flag(Flag::C, !c);
                 1
             },
 
-            // SUB (HL)
-            0x96 => {
-                let n = read_byte(self.reg.hl(), mem);
-                alu_sub(&mut self.reg, n);
-                2
-            },
-
-            // SBC A, C
-            0x99 => {
-                let n = self.reg.c;
-                alu_sbc(&mut self.reg, n);
-                1
-            },
-    
-            // AND C
-            0xA1 => {
-                let n = self.reg.c;
-                alu_and(&mut self.reg, n);
+            // HALT
+            0x76 => {
+                // Without IME a pending interrupt ends it right away. The
+                // bug that then reads the next opcode twice isn't emulated.
+                self.halted = mem[0xFF0F] & mem[0xFFFF] & 0x1F == 0 || self.reg.ime;
                 1
             },
 
-            // AND H
-            0xA4 => {
-                let n = self.reg.h;
-                alu_and(&mut self.reg, n);
-                1
+            // LD r, r'
+            0x40..=0x7F => {
+                let src = opcode & 0x07;
+                let dst = (opcode >> 3) & 0x07;
+                let val = self.read_r8(src, mem);
+                self.write_r8(dst, val, mem);
+                if src == 6 || dst == 6 { 2 } else { 1 }
             },
 
-            // AND A
-            0xA7 => {
-                let n = self.reg.a;
-                alu_and(&mut self.reg, n);
-                1
-            },
-    
-            // XOR C
-            0xA9 => {
-                let n = self.reg.c;
-                alu_xor(&mut self.reg, n);
-                1
-            },
-    
-            // XOR A
-            0xAF => {
-                let n = self.reg.a;
-                alu_xor(&mut self.reg, n);
-                1
-            },
-    
-            // OR B
-            0xB0 => {
-                let n = self.reg.b;
-                alu_or(&mut self.reg, n);
-                1
-            },
-    
-            // OR C
-            0xB1 => {
-                let n = self.reg.c;
-                alu_or(&mut self.reg, n);
-                1
-            },
-    
-            // CP A
-            0xBF => {
-                let n = self.reg.a;
-                alu_cp(&mut self.reg, n);
-                1
+            // ADD, ADC, SUB, SBC, AND, XOR, OR and CP with A and r
+            0x80..=0xBF => {
+                let src = opcode & 0x07;
+                let n = self.read_r8(src, mem);
+                match (opcode >> 3) & 0x07 {
+                    0 => alu_add(&mut self.reg, n),
+                    1 => alu_adc(&mut self.reg, n),
+                    2 => alu_sub(&mut self.reg, n),
+                    3 => alu_sbc(&mut self.reg, n),
+                    4 => alu_and(&mut self.reg, n),
+                    5 => alu_xor(&mut self.reg, n),
+                    6 => alu_or(&mut self.reg, n),
+                    _ => alu_cp(&mut self.reg, n)
+                }
+                if src == 6 { 2 } else { 1 }
             },
 
-            // RET NZ
-            0xC0 => {
-                if !self.reg.get_flag(Flag::Z) {
-                    self.reg.pc = self.pop_stack(mem);
-                    return 5;
-                } else {
-                    self.reg.pc += 1;
-                    2
-                }
+            // RET cc
+            0xC0 | 0xC8 | 0xD0 | 0xD8 => {
+                let taken = self.condition(opcode >> 3);
+                self.ret(taken, mem)
             },
 
             // POP BC
@@ -775,11 +582,20 @@ impl Cpu {
                 self.reg.set_bc(val);
                 3
             },
+
+            // JP cc, a16
+            0xC2 | 0xCA | 0xD2 | 0xDA => {
+                let taken = self.condition(opcode >> 3);
+                self.jp(taken, mem)
+            },
     
             // JP a16
-            0xC3 => {
-                self.reg.pc = self.next_word(mem);
-                4
+            0xC3 => self.jp(true, mem),
+
+            // CALL cc, a16
+            0xC4 | 0xCC | 0xD4 | 0xDC => {
+                let taken = self.condition(opcode >> 3);
+                self.call(taken, mem)
             },
 
             // PUSH BC
@@ -795,15 +611,9 @@ impl Cpu {
                 2
             },
 
-            // RET Z
-            0xC8 => {
-                if self.reg.get_flag(Flag::Z) {
-                    self.reg.pc = self.pop_stack(mem);
-                    return 5;
-                } else {
-                    self.reg.pc += 1;
-                    2
-                }
+            // RST n
+            0xC7 | 0xCF | 0xD7 | 0xDF | 0xE7 | 0xEF | 0xF7 | 0xFF => {
+                self.rst((opcode & 0x38) as u16, mem)
             },
     
             // RET
@@ -812,17 +622,6 @@ impl Cpu {
                 self.reg.pc = adr;
                 4
             },
-
-            // JP Z, a16
-            0xCA => {
-                if self.reg.get_flag(Flag::Z) {
-                    self.reg.pc = self.next_word(mem);
-                    return 4;
-                } else {
-                    self.reg.pc += 2;
-                    3
-                }
-            },
     
             // CALL CB
             0xCB => {
@@ -831,10 +630,13 @@ impl Cpu {
             },
     
             // CALL a16
-            0xCD => {
-                self.push_stack(self.reg.pc + 2, mem);
-                self.reg.pc = self.next_word(mem);
-                6
+            0xCD => self.call(true, mem),
+
+            // ADC A, d8
+            0xCE => {
+                let n = self.next_byte(mem);
+                alu_adc(&mut self.reg, n);
+                2
             },
 
             // POP DE
@@ -843,15 +645,6 @@ impl Cpu {
                 self.reg.set_de(val);
                 3
             },
-    
-            // JP NC, a16
-            0xD2 => {
-                if self.reg.get_flag(Flag::C) {
-                    self.reg.pc = self.next_word(mem);
-                    return 4
-                }
-                3
-            },
 
             // PUSH DE
             0xD5 => {
@@ -866,19 +659,20 @@ impl Cpu {
                 2
             },
 
+            // RETI
+            0xD9 => {
+                self.reg.pc = self.pop_stack(mem);
+                // Unlike EI without a delay
+                self.reg.ime = true;
+                4
+            },
+
             // SBC A, d8
             0xDE => {
                 let n = self.next_byte(mem);
                 alu_sbc(&mut self.reg, n);
                 2
             },
-
-            // RETI
-            0xD9 => {
-                self.reg.pc = self.pop_stack(mem);
-                self.set_ime(true);
-                4
-            },
     
             // LDH (a8), A
             0xE0 => {
@@ -914,6 +708,13 @@ impl Cpu {
                 2
             },
 
+            // ADD SP, r8
+            0xE8 => {
+                let n = self.next_byte(mem) as i8;
+                self.reg.sp = alu_add_sp(&mut self.reg, n);
+                4
+            },
+
             // JP HL
             0xE9 => {
                 self.reg.pc = self.reg.hl();
@@ -927,11 +728,11 @@ impl Cpu {
                 4
             },
 
-            // RST 28H
-            0xEF => {
-                self.push_stack(self.reg.pc, mem);
-                self.reg.pc = 0x0028;
-                4
+            // XOR d8
+            0xEE => {
+                let n = self.next_byte(mem);
+                alu_xor(&mut self.reg, n);
+                2
             },
     
             // LDH A, (a8)
@@ -948,10 +749,20 @@ impl Cpu {
                 self.reg.set_af(val);
                 3
             },
+
+            // LD A, (C)
+            0xF2 => {
+                let adr = 0xFF00 + self.reg.c as u16;
+                self.reg.a = read_byte(adr, mem);
+                2
+            },
     
             // DI
             0xF3 => {
-                self.set_ime(false);
+                // Takes effect at once, also cancelling an EI just before
+                self.reg.ime = false;
+                self.ime_set = None;
+                self.ime_delay = 0;
                 1
             },
 
@@ -961,6 +772,27 @@ impl Cpu {
                 4
             },
 
+            // OR d8
+            0xF6 => {
+                let n = self.next_byte(mem);
+                alu_or(&mut self.reg, n);
+                2
+            },
+
+            // LD HL, SP + r8
+            0xF8 => {
+                let n = self.next_byte(mem) as i8;
+                let val = alu_add_sp(&mut self.reg, n);
+                self.reg.set_hl(val);
+                3
+            },
+
+            // LD SP, HL
+            0xF9 => {
+                self.reg.sp = self.reg.hl();
+                2
+            },
+
             // LD A, (a16)
             0xFA => {
                 let adr = self.next_word(mem);
@@ -981,12 +813,200 @@ impl Cpu {
                 2
             },
     
-            // Instruction not implemented
+            // 0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC and
+            // 0xFD aren't instructions, the CPU stops responding
             _ => {
-                panic!("unsupported instruction: {:#04x}", opcode);
+                let adr = self.reg.pc.wrapping_sub(1);
+                warn!("CPU hung on illegal opcode {:#04x} at {:#06x}", opcode, adr);
+                self.lockup = Some((adr, opcode));
+                1
             }
         }
     }
 }
 
+impl Default for Cpu {
+    fn default() -> Cpu {
+        Cpu::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs `program` from 0xC000 until it steps past its end
+    fn run(program: &[u8]) -> (Cpu, Memory) {
+        let mut mem = Memory::new();
+        mem[0xC000..0xC000 + program.len()].copy_from_slice(program);
+        let mut cpu = Cpu::new();
+        cpu.reg.pc = 0xC000;
+        cpu.reg.sp = 0xDFFE;
+        for _ in 0..100 {
+            if cpu.reg.pc >= 0xC000 + program.len() as u16 || cpu.lockup.is_some() {
+                break;
+            }
+            cpu.tick(&mut mem);
+        }
+        (cpu, mem)
+    }
+
+    #[test]
+    fn test_conditional_flow() {
+        let (cpu, _) = run(&[
+            0xAF,             // XOR A
+            0x20, 0x02,       // JR NZ, +2
+            0x06, 0x11,       // LD B, 0x11
+            0xCA, 0x0A, 0xC0, // JP Z, 0xC00A
+            0x06, 0x22,       // LD B, 0x22
+            0xD4, 0x10, 0xC0, // CALL NC, 0xC010
+            0x18, 0x04,       // JR +4
+            0x00,
+            0x0E, 0x33,       // LD C, 0x33
+            0xC9              // RET
+        ]);
+        assert_eq!(cpu.reg.b, 0x11);
+        assert_eq!(cpu.reg.c, 0x33);
+        assert_eq!(cpu.reg.sp, 0xDFFE);
+    }
+
+    #[test]
+    fn test_hl_operands() {
+        let (cpu, mem) = run(&[
+            0x21, 0x00, 0xC1, // LD HL, 0xC100
+            0x36, 0x05,       // LD (HL), 0x05
+            0x3E, 0x03,       // LD A, 0x03
+            0x86,             // ADD A, (HL)
+            0x47,             // LD B, A
+            0xBE,             // CP (HL)
+            0xCB, 0x26,       // SLA (HL)
+            0xCB, 0x7E,       // BIT 7, (HL)
+            0x17,             // RLA
+            0x2E, 0x42        // LD L, 0x42
+        ]);
+        assert_eq!(cpu.reg.b, 0x08);
+        assert_eq!(cpu.reg.a, 0x10);
+        assert_eq!(mem[0xC100], 0x0A);
+        assert_eq!(cpu.reg.hl(), 0xC142);
+    }
+
+    #[test]
+    fn test_halt() {
+        let mut mem = Memory::new();
+        mem[0xC000..0xC004].copy_from_slice(&[0xF3, 0x76, 0x3E, 0x01]); // DI; HALT; LD A, 0x01
+        mem[0xFF0F] = 0x00;
+        mem[0xFFFF] = 0x04;
+        let mut cpu = Cpu::new();
+        cpu.reg.pc = 0xC000;
+        for _ in 0..10 {
+            cpu.tick(&mut mem);
+        }
+        assert!(cpu.halted);
+        assert_eq!(cpu.reg.pc, 0xC002);
+
+        // Wakes up without IME and carries on
+        mem[0xFF0F] = 0x04;
+        cpu.tick(&mut mem);
+        assert!(!cpu.halted);
+        assert_eq!(cpu.reg.a, 0x01);
+    }
+
+    #[test]
+    fn test_ret_conditions() {
+        let mut program = vec![0; 0x28];
+        program[0x00..0x0A].copy_from_slice(&[
+            0xAF,             // XOR A
+            0xCD, 0x10, 0xC0, // CALL 0xC010
+            0x37,             // SCF
+            0xCD, 0x18, 0xC0, // CALL 0xC018
+            0x18, 0x1E        // JR +30, to the end
+        ]);
+        program[0x10..0x16].copy_from_slice(&[
+            0xC0,             // RET NZ, not taken
+            0x06, 0x11,       // LD B, 0x11
+            0xC8,             // RET Z
+            0x06, 0xFF        // LD B, 0xFF
+        ]);
+        program[0x18..0x1E].copy_from_slice(&[
+            0xD0,             // RET NC, not taken
+            0x0E, 0x22,       // LD C, 0x22
+            0xD8,             // RET C
+            0x0E, 0xFF        // LD C, 0xFF
+        ]);
+        let (cpu, _) = run(&program);
+        assert_eq!(cpu.reg.b, 0x11);
+        assert_eq!(cpu.reg.c, 0x22);
+        assert_eq!(cpu.reg.sp, 0xDFFE);
+        assert_eq!(cpu.reg.pc, 0xC028);
+    }
+
+    #[test]
+    fn test_carry_arithmetic() {
+        let (cpu, _) = run(&[
+            0x37,             // SCF
+            0x3E, 0x0F,       // LD A, 0x0F
+            0x06, 0xF0,       // LD B, 0xF0
+            0x88,             // ADC A, B
+            0x4F,             // LD C, A
+            0xDE, 0x00        // SBC A, 0x00
+        ]);
+        // 0x0F + 0xF0 + 1 carries out of both nibbles
+        assert_eq!(cpu.reg.c, 0x00);
+        // 0x00 - 0x00 - 1 borrows from both
+        assert_eq!(cpu.reg.a, 0xFF);
+        assert_eq!(cpu.reg.f, 0b01110000);
+    }
+
+    #[test]
+    fn test_daa() {
+        let (cpu, _) = run(&[
+            0x3E, 0x45,       // LD A, 0x45
+            0xC6, 0x38,       // ADD A, 0x38
+            0x27,             // DAA
+            0x47,             // LD B, A
+            0xD6, 0x84,       // SUB 0x84
+            0x27              // DAA
+        ]);
+        assert_eq!(cpu.reg.b, 0x83);
+        assert_eq!(cpu.reg.a, 0x99);
+        assert_eq!(cpu.reg.f, 0b01010000);
+
+        // 99 + 1 wraps to 00 with a carry
+        let (cpu, _) = run(&[0x3E, 0x99, 0xC6, 0x01, 0x27]);
+        assert_eq!(cpu.reg.a, 0x00);
+        assert_eq!(cpu.reg.f, 0b10010000);
+    }
 
+    #[test]
+    fn test_halt_interrupt() {
+        let mut mem = Memory::new();
+        mem[0xC000..0xC002].copy_from_slice(&[0xFB, 0x76]); // EI; HALT
+        mem[0xFF0F] = 0x00;
+        mem[0xFFFF] = 0x04;
+        let mut cpu = Cpu::new();
+        cpu.reg.pc = 0xC000;
+        cpu.reg.sp = 0xDFFE;
+        for _ in 0..10 {
+            cpu.tick(&mut mem);
+        }
+        assert!(cpu.halted);
+
+        // The timer interrupt wakes it up and is served right away
+        mem[0xFF0F] = 0x04;
+        cpu.tick(&mut mem);
+        assert!(!cpu.halted);
+        assert!(!cpu.reg.ime);
+        assert_eq!(cpu.reg.pc, 0x0050);
+        assert_eq!(mem[0xFF0F] & 0x04, 0);
+        assert_eq!(read_word(cpu.reg.sp, &mem), 0xC002);
+    }
+
+    #[test]
+    fn test_illegal_opcode() {
+        let (mut cpu, mut mem) = run(&[0x3E, 0x01, 0xDD, 0x3E, 0x02]);
+        assert_eq!(cpu.lockup, Some((0xC002, 0xDD)));
+        cpu.tick(&mut mem);
+        assert_eq!(cpu.reg.a, 0x01);
+        assert_eq!(cpu.check().unwrap_err(), "CPU hung on illegal opcode 0xdd at 0xc002");
+    }
+}
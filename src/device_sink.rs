@@ -0,0 +1,55 @@
+use crate::audio_sink::*;
+
+use cpal::traits::{ DeviceTrait, HostTrait, StreamTrait };
+use log::warn;
+use std::collections::VecDeque;
+use std::sync::{ Arc, Mutex };
+
+// Plays through the default output device. Samples wait in a queue the
+// device's callback drains, silence fills in when it runs dry.
+pub struct DeviceSink {
+    queue: Arc<Mutex<VecDeque<f32>>>,
+    // Samples past this are dropped, when running faster than real time
+    capacity: usize,
+    _stream: cpal::Stream
+}
+
+impl DeviceSink {
+    pub fn open(sample_rate: u32) -> Result<DeviceSink, String> {
+        let device = cpal::default_host().default_output_device().ok_or("no audio output device")?;
+        let config = cpal::StreamConfig {
+            channels: 2,
+            sample_rate: cpal::SampleRate(sample_rate),
+            buffer_size: cpal::BufferSize::Default
+        };
+
+        let queue = Arc::new(Mutex::new(VecDeque::new()));
+        let device_queue = queue.clone();
+        let stream = device.build_output_stream(
+            &config,
+            move |data: &mut [f32], _| {
+                let mut queue = device_queue.lock().unwrap();
+                for sample in data.iter_mut() {
+                    *sample = queue.pop_front().unwrap_or(0.0);
+                }
+            },
+            |why| warn!("audio output failed: {}", why),
+            None
+        ).map_err(|why| why.to_string())?;
+        stream.play().map_err(|why| why.to_string())?;
+
+        Ok(DeviceSink { queue, capacity: sample_rate as usize, _stream: stream })
+    }
+}
+
+impl AudioSink for DeviceSink {
+    fn push(&mut self, samples: &[f32]) {
+        let mut queue = self.queue.lock().unwrap();
+        let room = self.capacity.saturating_sub(queue.len());
+        queue.extend(&samples[..samples.len().min(room)]);
+    }
+
+    fn queued(&self) -> Option<usize> {
+        Some(self.queue.lock().unwrap().len() / 2)
+    }
+}
@@ -0,0 +1,331 @@
+// DMG-07 Four Player Adapter
+//
+// The adapter drives the serial clock, every connected Game Boy runs on
+// external clock. It starts in the ping phase, repeatedly sending
+// 0xFE followed by three STAT bytes. Each Game Boy answers with ACK1 and ACK2
+// (both 0x88) followed by the RATE and SIZE it wants, only player 1's are used.
+// STAT holds the connected players in the upper nibble and the id of the
+// receiving player in the lower bits.
+//
+// Player 1 answering 0xAA requests the transmission phase, the adapter then
+// sends a packet of 0xCC and switches over. In the transmission phase each
+// packet is SIZE * 4 bytes long. Every Game Boy sends its SIZE bytes at the
+// start of a packet, and the adapter sends everyone the data collected in the
+// previous packet, player 1 first. Player 1 sending 0xFF four times in a row
+// restarts the ping phase.
+
+use crate::gameboy::*;
+use crate::link::*;
+use crate::serial::*;
+
+const MAX_PLAYERS: usize = 4;
+
+const PING_HEADER: u8 = 0xFE;
+const ACK: u8 = 0x88;
+const START_REQUEST: u8 = 0xAA;
+const TRANSITION: u8 = 0xCC;
+const RESTART_REQUEST: u8 = 0xFF;
+
+// Time between bytes, roughly 2 ms. RATE adds about 1 ms per step in the
+// transmission phase.
+const PING_INTERVAL: u32 = 2048;
+const RATE_STEP: u32 = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    Ping,
+    Transition,
+    Transmission
+}
+
+pub struct FourPlayerAdapter {
+    ports: Vec<LocalLink>,
+    phase: Phase,
+    clocks: u32,
+    // Position of the last byte sent in the current packet
+    index: usize,
+    acked: [bool; MAX_PLAYERS],
+    connected: [bool; MAX_PLAYERS],
+    rate: u8,
+    size: u8,
+    start_requested: bool,
+    restart_count: u8,
+    received: [[u8; MAX_PLAYERS]; MAX_PLAYERS],
+    // Data sent to every player in the current packet
+    combined: Vec<u8>
+}
+
+impl FourPlayerAdapter {
+    pub fn new() -> FourPlayerAdapter {
+        FourPlayerAdapter {
+            ports: Vec::new(),
+            phase: Phase::Ping,
+            clocks: 0,
+            index: 3,
+            acked: [false; MAX_PLAYERS],
+            connected: [false; MAX_PLAYERS],
+            rate: 0,
+            size: 1,
+            start_requested: false,
+            restart_count: 0,
+            received: [[0; MAX_PLAYERS]; MAX_PLAYERS],
+            combined: Vec::new()
+        }
+    }
+
+    // Plug in the next player, returns the Game Boy end of the cable
+    pub fn plug(&mut self) -> LocalLink {
+        assert!(self.ports.len() < MAX_PLAYERS, "DMG-07 only has {} ports", MAX_PLAYERS);
+        let (adapter_end, gb_end) = local_link_pair();
+        self.ports.push(adapter_end);
+        gb_end
+    }
+
+    pub fn connect(&mut self, gb: &mut GameBoy) {
+        let link = self.plug();
+        gb.serial.connect(Box::new(link));
+    }
+
+    // Run one instruction on the first player and catch the others up,
+    // the adapter follows the first player's clock.
+    // Returns the m-cycle length of the first player's instruction.
+    pub fn step(&mut self, players: &mut [GameBoy]) -> u16 {
+        let (first, others) = players.split_first_mut().unwrap();
+        let op_cycles = first.step_instruction();
+        for gb in others.iter_mut() {
+            while gb.cycles < first.cycles {
+                gb.step_instruction();
+            }
+        }
+        for _cycle in 0..op_cycles {
+            self.tick();
+        }
+        op_cycles
+    }
+
+    pub fn tick(&mut self) {
+        self.poll_replies();
+
+        self.clocks += 1;
+        if self.clocks < self.interval() {
+            return;
+        }
+        self.clocks = 0;
+
+        self.index += 1;
+        if self.index >= self.packet_len() {
+            self.index = 0;
+            self.begin_packet();
+        }
+
+        for player in 0..self.ports.len() {
+            let byte = self.packet_byte(player);
+            self.ports[player].send(LinkMessage::Transfer(byte));
+        }
+    }
+
+    fn interval(&self) -> u32 {
+        match self.phase {
+            Phase::Ping => PING_INTERVAL,
+            _ => PING_INTERVAL + (self.rate & 0x0F) as u32 * RATE_STEP
+        }
+    }
+
+    fn packet_len(&self) -> usize {
+        match self.phase {
+            Phase::Transmission => self.size as usize * MAX_PLAYERS,
+            _ => 4
+        }
+    }
+
+    fn begin_packet(&mut self) {
+        match self.phase {
+            Phase::Ping => {
+                if self.start_requested {
+                    self.start_requested = false;
+                    self.phase = Phase::Transition;
+                }
+            },
+            Phase::Transition => {
+                self.phase = Phase::Transmission;
+                self.received = [[0; MAX_PLAYERS]; MAX_PLAYERS];
+                self.combined = vec![0; self.packet_len()];
+            },
+            Phase::Transmission => {
+                let size = self.size as usize;
+                self.combined = self.received.iter()
+                    .flat_map(|data| data[..size].iter().copied())
+                    .collect();
+                self.received = [[0; MAX_PLAYERS]; MAX_PLAYERS];
+            }
+        }
+    }
+
+    fn packet_byte(&self, player: usize) -> u8 {
+        match self.phase {
+            Phase::Ping => match self.index {
+                0 => PING_HEADER,
+                _ => self.stat(player)
+            },
+            Phase::Transition => TRANSITION,
+            Phase::Transmission => self.combined[self.index]
+        }
+    }
+
+    fn stat(&self, player: usize) -> u8 {
+        let mask = self.connected.iter().enumerate()
+            .fold(0, |mask, (i, &connected)| mask | ((connected as u8) << i));
+        (mask << 4) | (player as u8 + 1)
+    }
+
+    fn poll_replies(&mut self) {
+        for player in 0..self.ports.len() {
+            while let Some(msg) = self.ports[player].recv() {
+                if let LinkMessage::Reply(byte) = msg {
+                    self.on_reply(player, byte);
+                }
+            }
+        }
+    }
+
+    fn on_reply(&mut self, player: usize, byte: u8) {
+        match self.phase {
+            Phase::Ping => {
+                if player == 0 && byte == START_REQUEST {
+                    self.start_requested = true;
+                    return;
+                }
+                match self.index {
+                    0 => self.acked[player] = byte == ACK,
+                    1 => self.connected[player] = self.acked[player] && byte == ACK,
+                    2 if player == 0 => self.rate = byte,
+                    3 if player == 0 => self.size = byte.clamp(1, MAX_PLAYERS as u8),
+                    _ => {}
+                }
+            },
+            Phase::Transition => {},
+            Phase::Transmission => {
+                if self.index < self.size as usize {
+                    self.received[player][self.index] = byte;
+                }
+                if player == 0 {
+                    self.restart_count = match byte {
+                        RESTART_REQUEST => self.restart_count + 1,
+                        _ => 0
+                    };
+                    if self.restart_count == 4 {
+                        self.restart_count = 0;
+                        self.phase = Phase::Ping;
+                        self.index = 3; // next byte starts a new ping packet
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Default for FourPlayerAdapter {
+    fn default() -> FourPlayerAdapter {
+        FourPlayerAdapter::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs the adapter until it sends the next byte, each player answers
+    // with the byte returned by `answer`
+    fn exchange(adapter: &mut FourPlayerAdapter, players: &mut [LocalLink], answer: &[u8]) -> Vec<u8> {
+        let mut sent = vec![None; players.len()];
+        while sent.iter().any(|byte| byte.is_none()) {
+            adapter.tick();
+            for (i, link) in players.iter_mut().enumerate() {
+                if let Some(LinkMessage::Transfer(byte)) = link.recv() {
+                    sent[i] = Some(byte);
+                    link.send(LinkMessage::Reply(answer[i]));
+                }
+            }
+        }
+        // Let the adapter see the replies
+        adapter.tick();
+        sent.into_iter().map(|byte| byte.unwrap()).collect()
+    }
+
+    fn ping(adapter: &mut FourPlayerAdapter, players: &mut [LocalLink], rate: u8, size: u8) -> Vec<Vec<u8>> {
+        let n = players.len();
+        [ACK, ACK, rate, size].iter()
+            .map(|&byte| exchange(adapter, players, &vec![byte; n]))
+            .collect()
+    }
+
+    #[test]
+    fn test_ping_phase() {
+        let mut adapter = FourPlayerAdapter::new();
+        let mut players = vec![adapter.plug(), adapter.plug()];
+
+        let packet = ping(&mut adapter, &mut players, 0, 1);
+        assert_eq!(packet[0], vec![0xFE, 0xFE]);
+        assert_eq!(packet[1], vec![0x01, 0x02]);
+        assert_eq!(adapter.connected, [true, true, false, false]);
+
+        // Both players show up in the next ping
+        let packet = ping(&mut adapter, &mut players, 0, 1);
+        assert_eq!(packet[1], vec![0x31, 0x32]);
+        assert_eq!(adapter.phase, Phase::Ping);
+    }
+
+    #[test]
+    fn test_transmission_phase() {
+        let mut adapter = FourPlayerAdapter::new();
+        let mut players = vec![adapter.plug(), adapter.plug()];
+
+        ping(&mut adapter, &mut players, 0, 2);
+        for _ in 0..4 {
+            exchange(&mut adapter, &mut players, &[START_REQUEST, 0x00]);
+        }
+        assert_eq!(exchange(&mut adapter, &mut players, &[0, 0]), vec![TRANSITION; 2]);
+        for _ in 0..3 {
+            exchange(&mut adapter, &mut players, &[0, 0]);
+        }
+
+        // Each player sends 2 bytes, padded to the 8 byte packet
+        let data = [[0x11, 0x21], [0x12, 0x22], [0x00, 0x00], [0x00, 0x00],
+                    [0x00, 0x00], [0x00, 0x00], [0x00, 0x00], [0x00, 0x00]];
+        for answer in data.iter() {
+            exchange(&mut adapter, &mut players, answer);
+        }
+        assert_eq!(adapter.phase, Phase::Transmission);
+
+        // Collected data comes back in the next packet
+        let received: Vec<Vec<u8>> = data.iter()
+            .map(|_| exchange(&mut adapter, &mut players, &[0, 0]))
+            .collect();
+        let expected = [0x11, 0x12, 0x21, 0x22, 0x00, 0x00, 0x00, 0x00];
+        for (byte, sent) in expected.iter().zip(received.iter()) {
+            assert_eq!(sent, &vec![*byte; 2]);
+        }
+    }
+
+    #[test]
+    fn test_restart_request() {
+        let mut adapter = FourPlayerAdapter::new();
+        let mut players = vec![adapter.plug()];
+
+        ping(&mut adapter, &mut players, 0, 1);
+        for _ in 0..4 {
+            exchange(&mut adapter, &mut players, &[START_REQUEST]);
+        }
+        for _ in 0..4 {
+            exchange(&mut adapter, &mut players, &[0]);
+        }
+
+        exchange(&mut adapter, &mut players, &[RESTART_REQUEST]);
+        assert_eq!(adapter.phase, Phase::Transmission);
+        for _ in 0..3 {
+            exchange(&mut adapter, &mut players, &[RESTART_REQUEST]);
+        }
+        assert_eq!(adapter.phase, Phase::Ping);
+        assert_eq!(exchange(&mut adapter, &mut players, &[ACK]), vec![PING_HEADER]);
+    }
+}
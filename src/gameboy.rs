@@ -0,0 +1,293 @@
+use crate::mmu::*;
+use crate::cpu::*;
+use crate::gpu::*;
+use crate::serial::*;
+use crate::timer::*;
+use crate::registers::*;
+use crate::cartridge::*;
+use crate::model::*;
+use crate::compat_palette::*;
+use crate::sgb::*;
+use crate::infrared::*;
+use crate::joypad::*;
+use crate::four_player::*;
+
+// 70224 T-cycles from one VBlank to the next, 59.73 Hz
+pub const M_CYCLES_PER_FRAME: u64 = 17556;
+
+// A single emulated Game Boy. The APU and the cartridge's ROM live in
+// memory, which is the bus every other part goes through.
+pub struct GameBoy {
+    pub header: Header,
+    pub cpu: Cpu,
+    pub mem: Memory,
+    pub gpu: Gpu,
+    pub serial: Serial,
+    pub timer: Timer,
+    pub infrared: Infrared,
+    // Total m-cycles run since power on, at normal speed
+    pub cycles: u64,
+    // Double speed only ticks the PPU on every other CPU m-cycle
+    odd_cycle: bool
+}
+
+impl GameBoy {
+    // Checks the rom can run, model picked from the cartridge header
+    pub fn from_rom_bytes(rom: &[u8]) -> Result<GameBoy, String> {
+        if rom.len() < 0x0150 {
+            return Err(format!("rom too short for a cartridge header ({} bytes)", rom.len()));
+        }
+        let header = Header::parse(rom);
+        if header.cartridge_type != 0x00 {
+            return Err(format!("cartridge type {:#04x} not supported, only roms without an MBC run", header.cartridge_type));
+        }
+        Ok(GameBoy::new(rom))
+    }
+
+    // Model picked from the cartridge header
+    pub fn new(rom: &[u8]) -> GameBoy {
+        let model = Model::detect(&Header::parse(rom));
+        GameBoy::with_model(rom, model)
+    }
+
+    pub fn with_model(rom: &[u8], model: Model) -> GameBoy {
+        let header = Header::parse(rom);
+        let cgb_mode = model.is_cgb() && header.supports_cgb();
+
+        let mut mem = Memory::with_model(model, cgb_mode);
+        init_memory(&mut mem);
+        init_model_io(&mut mem);
+        if model.is_cgb() && !cgb_mode {
+            load_compat_palette(&mut mem, CompatPalette::detect(&header));
+        }
+        if model.is_sgb() && header.supports_sgb() {
+            mem.sgb = Some(Box::new(Sgb::new()));
+        }
+
+        // Copy rom data to memory
+        let len = rom.len().min(0x8000);
+        mem[..len].copy_from_slice(&rom[..len]);
+
+        let mut cpu = Cpu::new();
+        cpu.reg = Registers::after_boot(model, &header);
+
+        GameBoy {
+            header,
+            cpu,
+            mem,
+            gpu: Gpu::new(),
+            serial: Serial::new(),
+            timer: Timer::new(),
+            infrared: Infrared::new(),
+            cycles: 0,
+            odd_cycle: false
+        }
+    }
+
+    // Start from power on, running `boot_rom` before the cartridge
+    pub fn with_boot_rom(rom: &[u8], model: Model, boot_rom: &[u8]) -> Result<GameBoy, String> {
+        let expected = if model.is_cgb() { 0x900 } else { 0x100 };
+        if boot_rom.len() != expected {
+            return Err(format!("boot rom has {} bytes, one for {} has {}", boot_rom.len(), model, expected));
+        }
+
+        let mut gb = GameBoy::with_model(rom, model);
+        gb.mem.map_boot_rom(boot_rom);
+        gb.mem[0xFF40] = 0x00;
+        gb.cpu.reg = Registers::power_on();
+        Ok(gb)
+    }
+
+    // Run next instruction, returns the time it took in normal speed m-cycles.
+    // In double speed the CPU, timer and serial port run twice as fast while
+    // the PPU keeps its pace.
+    pub fn step_instruction(&mut self) -> u16 {
+        // Time the CPU spent halted for DMA is added on
+        let op_cycles = self.cpu.tick(&mut self.mem) + std::mem::take(&mut self.mem.dma_stall);
+        let mut elapsed = 0;
+        for _cycle in 0..op_cycles {
+            self.timer.tick(&mut self.mem);
+            self.serial.tick(&mut self.mem);
+            self.infrared.tick(&mut self.mem);
+
+            self.odd_cycle = !self.odd_cycle;
+            if !self.mem.double_speed || self.odd_cycle {
+                self.gpu.tick(&mut self.mem);
+                self.mem.apu.tick(self.mem.div, self.mem.double_speed);
+                elapsed += 1;
+            }
+        }
+        self.cycles += elapsed as u64;
+        elapsed
+    }
+
+    // Run up to the end of the current frame
+    pub fn run_frame(&mut self) {
+        let end = self.frame_end();
+        while self.cycles < end {
+            self.step_instruction();
+        }
+    }
+
+    // End of the current frame in m-cycles, frames are counted from power on
+    pub fn frame_end(&self) -> u64 {
+        (self.cycles / M_CYCLES_PER_FRAME + 1) * M_CYCLES_PER_FRAME
+    }
+
+    // Screen as RGB555 without the SGB border. The PPU draws into it line by
+    // line, it only holds a whole frame between run_frame calls.
+    pub fn framebuffer(&self) -> &[u16] {
+        &self.gpu.framebuffer
+    }
+
+    // Size of the image `render` draws, which includes the SGB border
+    pub fn screen_size(&self) -> (usize, usize) {
+        match self.mem.sgb {
+            Some(_) => (BORDER_WIDTH, BORDER_HEIGHT),
+            None => (SCREEN_WIDTH, SCREEN_HEIGHT)
+        }
+    }
+
+    // Draw the screen as 0RGB into `buffer`
+    pub fn render(&mut self, buffer: &mut [u32]) {
+        match self.mem.sgb.as_mut() {
+            Some(sgb) => sgb.render(&self.gpu.framebuffer, self.gpu.color_correction, buffer),
+            None => self.gpu.to_rgb888(buffer)
+        }
+    }
+
+    // Buttons of the first controller, SGB multiplayer goes through joypad.rs
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        set_button(&mut self.mem, 0, button, pressed);
+    }
+
+    // Samples produced since the last call, interleaved left and right. Empty
+    // when the APU has a sink.
+    pub fn audio_samples(&mut self) -> Vec<f32> {
+        self.mem.apu.take_samples()
+    }
+}
+
+// Run instances wired together up to the end of the first one's frame. The
+// others are caught up after each of its instructions, a DMG-07 `adapter`
+// between them follows its clock.
+pub fn run_frame_linked(players: &mut [GameBoy], adapter: Option<&mut FourPlayerAdapter>) {
+    let end = players[0].frame_end();
+    match adapter {
+        Some(adapter) => {
+            while players[0].cycles < end {
+                adapter.step(players);
+            }
+        },
+        None => {
+            let (first, others) = players.split_first_mut().unwrap();
+            while first.cycles < end {
+                first.step_instruction();
+                for gb in others.iter_mut() {
+                    while gb.cycles < first.cycles {
+                        gb.step_instruction();
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_double_speed() {
+        let mut rom = vec![0; 0x8000];
+        let program = [
+            0x3E, 0x01, // LD A, 0x01
+            0xE0, 0x4D, // LDH (KEY1), A
+            0x10, 0x00, // STOP
+            0x18, 0xFE  // JR -2
+        ];
+        rom[0x0100..0x0100 + program.len()].copy_from_slice(&program);
+        rom[0x0143] = 0x80;
+        let mut gb = GameBoy::new(&rom);
+
+        for _ in 0..3 {
+            gb.step_instruction();
+        }
+        assert!(gb.mem.double_speed);
+
+        // JR takes 3 m-cycles, the PPU sees half of them
+        let cycles = gb.cycles;
+        for _ in 0..100 {
+            gb.step_instruction();
+        }
+        assert_eq!(gb.cycles - cycles, 150);
+    }
+
+    #[test]
+    fn test_boot_rom() {
+        let mut boot = vec![0; 0x100];
+        let program = [
+            0x31, 0xFE, 0xFF, // LD SP, 0xFFFE
+            0x21, 0x00, 0xC0, // LD HL, 0xC000
+            0x36, 0x21,       // LD (HL), 0x21
+            0x3E, 0x01,       // LD A, 0x01
+            0x86,             // ADD A, (HL)
+            0x17,             // RLA
+            0xBE,             // CP (HL)
+            0x2E, 0x10,       // LD L, 0x10
+            0x77,             // LD (HL), A
+            0xC3, 0xFC, 0x00  // JP 0x00FC
+        ];
+        boot[..program.len()].copy_from_slice(&program);
+        // LD A, 0x01; LDH (BOOT), A
+        boot[0xFC..].copy_from_slice(&[0x3E, 0x01, 0xE0, 0x50]);
+
+        let mut rom = vec![0; 0x8000];
+        rom[0x0100..0x0102].copy_from_slice(&[0x18, 0xFE]); // JR -2
+        assert!(GameBoy::with_boot_rom(&rom, Model::Dmg, &boot[..0x80]).is_err());
+
+        let mut gb = GameBoy::with_boot_rom(&rom, Model::Dmg, &boot).unwrap();
+        while gb.cpu.reg.pc != 0x0100 && gb.cycles < 100 {
+            gb.step_instruction();
+        }
+        assert_eq!(gb.cpu.reg.pc, 0x0100);
+        assert_eq!(gb.mem[0xC010], 0x44);
+        assert!(gb.cpu.check().is_ok());
+    }
+
+    #[test]
+    fn test_run_frame() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0100..0x0102].copy_from_slice(&[0x18, 0xFE]); // JR -2
+        assert!(GameBoy::from_rom_bytes(&rom[..0x100]).is_err());
+        rom[0x0147] = 0x01;
+        assert!(GameBoy::from_rom_bytes(&rom).is_err());
+        rom[0x0147] = 0x00;
+
+        let mut gb = GameBoy::from_rom_bytes(&rom).unwrap();
+        gb.run_frame();
+        assert_eq!(gb.cycles, M_CYCLES_PER_FRAME);
+        gb.run_frame();
+        assert_eq!(gb.cycles, M_CYCLES_PER_FRAME * 2);
+        assert_eq!(gb.framebuffer().len(), SCREEN_WIDTH * SCREEN_HEIGHT);
+
+        let samples = gb.audio_samples();
+        assert!(samples.len() / 2 > 1300 && samples.len() / 2 < 1500);
+    }
+
+    #[test]
+    fn test_run_frame_linked() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0100..0x0102].copy_from_slice(&[0x18, 0xFE]); // JR -2
+        let mut players = vec![GameBoy::new(&rom), GameBoy::new(&rom)];
+        run_frame_linked(&mut players, None);
+        assert_eq!(players[0].cycles, M_CYCLES_PER_FRAME);
+        assert_eq!(players[1].cycles, M_CYCLES_PER_FRAME);
+
+        let mut adapter = FourPlayerAdapter::new();
+        players.extend([GameBoy::new(&rom), GameBoy::new(&rom)]);
+        run_frame_linked(&mut players, Some(&mut adapter));
+        assert_eq!(players[0].cycles, M_CYCLES_PER_FRAME * 2);
+        assert!(players[3].cycles >= M_CYCLES_PER_FRAME);
+    }
+}
@@ -0,0 +1,228 @@
+// Game Boy Sound files: a music driver ripped from a game with a small header
+// telling where to load it and which routines to call.
+// https://ocremix.org/info/GBS_Format_Specification
+//
+// The data is placed at its load address in a banked ROM image. Below it go
+// jumps for the RST vectors, which GBS files expect relocated to the load
+// address, and a driver: init is called with the song number in A and
+// returns into an idle loop, the VBlank or timer interrupt calls play.
+
+use crate::gameboy::*;
+use crate::model::*;
+
+pub const HEADER_LEN: usize = 0x70;
+
+// Driver routines, below the lowest allowed load address
+const IDLE_LOOP: u16 = 0x0060;
+const VBLANK_VECTOR: u16 = 0x0040;
+const TIMER_VECTOR: u16 = 0x0050;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GbsHeader {
+    pub songs: u8,
+    // 1-based
+    pub first_song: u8,
+    pub load: u16,
+    pub init: u16,
+    pub play: u16,
+    pub stack: u16,
+    pub tma: u8,
+    // Bit 2 plays on the timer instead of VBlank, bit 7 runs in CGB double speed
+    pub tac: u8,
+    pub title: String,
+    pub author: String,
+    pub copyright: String
+}
+
+impl GbsHeader {
+    pub fn parse(data: &[u8]) -> Result<GbsHeader, String> {
+        if data.len() < HEADER_LEN {
+            return Err(format!("file too short for a GBS header ({} bytes)", data.len()));
+        }
+        if &data[0..3] != b"GBS" {
+            return Err("not a GBS file".to_string());
+        }
+        if data[3] != 1 {
+            return Err(format!("unsupported GBS version {}", data[3]));
+        }
+
+        let word = |adr: usize| u16::from_le_bytes([data[adr], data[adr + 1]]);
+        let text = |adr: usize| {
+            let field = &data[adr..adr + 0x20];
+            let len = field.iter().position(|c| *c == 0).unwrap_or(field.len());
+            String::from_utf8_lossy(&field[..len]).into_owned()
+        };
+
+        let header = GbsHeader {
+            songs: data[0x04],
+            first_song: data[0x05],
+            load: word(0x06),
+            init: word(0x08),
+            play: word(0x0A),
+            stack: word(0x0C),
+            tma: data[0x0E],
+            tac: data[0x0F],
+            title: text(0x10),
+            author: text(0x30),
+            copyright: text(0x50)
+        };
+        if !(0x0400..0x8000).contains(&header.load) {
+            return Err(format!("load address {:#06x} outside 0x0400-0x7FFF", header.load));
+        }
+        if header.songs == 0 {
+            return Err("no songs in GBS file".to_string());
+        }
+        Ok(header)
+    }
+
+    pub fn uses_timer(&self) -> bool {
+        self.tac & 0x04 != 0
+    }
+}
+
+pub struct GbsPlayer {
+    pub header: GbsHeader,
+    rom: Vec<u8>,
+    pub gb: GameBoy
+}
+
+impl GbsPlayer {
+    // Loaded with the first song started
+    pub fn new(data: &[u8]) -> Result<GbsPlayer, String> {
+        let header = GbsHeader::parse(data)?;
+        let load = header.load as usize;
+
+        let mut rom = vec![0; load];
+        rom.extend_from_slice(&data[HEADER_LEN..]);
+
+        // JP load + n at each RST vector
+        for vector in (0x00..0x40).step_by(8) {
+            let [lo, hi] = (header.load + vector as u16).to_le_bytes();
+            rom[vector..vector + 3].copy_from_slice(&[0xC3, lo, hi]);
+        }
+        // CALL play; RETI at both interrupt vectors
+        let [lo, hi] = header.play.to_le_bytes();
+        for vector in [VBLANK_VECTOR, TIMER_VECTOR] {
+            let vector = vector as usize;
+            rom[vector..vector + 4].copy_from_slice(&[0xCD, lo, hi, 0xD9]);
+        }
+        // EI; JR -2
+        let idle = IDLE_LOOP as usize;
+        rom[idle..idle + 3].copy_from_slice(&[0xFB, 0x18, 0xFE]);
+
+        let first = header.first_song.max(1) - 1;
+        let mut player = GbsPlayer { header, rom, gb: GameBoy::with_model(&[], Model::Dmg) };
+        player.start_song(first);
+        Ok(player)
+    }
+
+    // Reset and call init for `song`, counted from 0
+    pub fn start_song(&mut self, song: u8) {
+        let header = &self.header;
+        let model = if header.tac & 0x80 != 0 { Model::Cgb } else { Model::Dmg };
+        let mut gb = GameBoy::with_model(&self.rom[..self.rom.len().min(0x8000)], model);
+        gb.mem.load_banked_rom(&self.rom);
+        gb.mem.double_speed = header.tac & 0x80 != 0;
+
+        gb.mem[0xFF06] = header.tma;
+        gb.mem[0xFF07] = header.tac & 0x07;
+        gb.mem[0xFF0F] = 0x00;
+        gb.mem[0xFFFF] = if header.uses_timer() { 0x04 } else { 0x01 };
+
+        // Returns into the idle loop, which enables interrupts
+        let [lo, hi] = IDLE_LOOP.to_le_bytes();
+        gb.cpu.reg.sp = header.stack.wrapping_sub(2);
+        gb.mem[gb.cpu.reg.sp as usize] = lo;
+        gb.mem[gb.cpu.reg.sp as usize + 1] = hi;
+        gb.cpu.reg.pc = header.init;
+        gb.cpu.reg.a = song;
+        gb.cpu.reg.ime = false;
+
+        // Audio keeps going to the same place
+        gb.mem.apu.take_output(&mut self.gb.mem.apu);
+        self.gb = gb;
+    }
+
+    // Run for `cycles` normal speed m-cycles, fails when the driver hangs the CPU
+    pub fn run(&mut self, cycles: u64) -> Result<(), String> {
+        let end = self.gb.cycles + cycles;
+        while self.gb.cycles < end {
+            self.gb.step_instruction();
+            self.gb.cpu.check()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Init sets NR50 to the song number, play counts calls in 0xC000
+    fn test_gbs(tac: u8) -> Vec<u8> {
+        let mut data = vec![0; HEADER_LEN];
+        data[0..4].copy_from_slice(b"GBS\x01");
+        data[0x04] = 3;
+        data[0x05] = 2;
+        data[0x06..0x08].copy_from_slice(&0x0400u16.to_le_bytes());
+        data[0x08..0x0A].copy_from_slice(&0x0400u16.to_le_bytes());
+        data[0x0A..0x0C].copy_from_slice(&0x0410u16.to_le_bytes());
+        data[0x0C..0x0E].copy_from_slice(&0xFFFEu16.to_le_bytes());
+        data[0x0E] = 0x00;
+        data[0x0F] = tac;
+        data[0x10..0x15].copy_from_slice(b"Songs");
+
+        let mut code = vec![0; 0x20];
+        // init: LDH (NR50), A; RET
+        code[0x00..0x03].copy_from_slice(&[0xE0, 0x24, 0xC9]);
+        // play: LD A, (0xC000); INC A; LD (0xC000), A; RET
+        code[0x10..0x18].copy_from_slice(&[0xFA, 0x00, 0xC0, 0x3C, 0xEA, 0x00, 0xC0, 0xC9]);
+        data.extend_from_slice(&code);
+        data
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let header = GbsHeader::parse(&test_gbs(0)).unwrap();
+        assert_eq!(header.songs, 3);
+        assert_eq!(header.play, 0x0410);
+        assert_eq!(header.title, "Songs");
+        assert!(!header.uses_timer());
+
+        let mut bad = test_gbs(0);
+        bad[0x07] = 0x00;
+        assert!(GbsHeader::parse(&bad).is_err());
+        assert!(GbsHeader::parse(b"GBS").is_err());
+    }
+
+    #[test]
+    fn test_play_on_vblank() {
+        let mut player = GbsPlayer::new(&test_gbs(0)).unwrap();
+        // A second at 59.7 Hz
+        player.run(1 << 20).unwrap();
+        assert_eq!(player.gb.mem.apu.read(0xFF24), 0x01);
+        assert!((59..=60).contains(&player.gb.mem[0xC000]));
+
+        player.start_song(2);
+        assert_eq!(player.gb.mem[0xC000], 0);
+        player.run(1000).unwrap();
+        assert_eq!(player.gb.mem.apu.read(0xFF24), 0x02);
+    }
+
+    #[test]
+    fn test_play_on_timer() {
+        // 4096 Hz, overflowing every 256 ticks
+        let mut player = GbsPlayer::new(&test_gbs(0x04)).unwrap();
+        player.run(1 << 20).unwrap();
+        assert!((15..=16).contains(&player.gb.mem[0xC000]));
+    }
+
+    #[test]
+    fn test_illegal_opcode() {
+        let mut data = test_gbs(0);
+        // play: an opcode that doesn't exist
+        data[HEADER_LEN + 0x10] = 0xFD;
+        let mut player = GbsPlayer::new(&data).unwrap();
+        assert_eq!(player.run(1 << 20).unwrap_err(), "CPU hung on illegal opcode 0xfd at 0x0410");
+    }
+}
@@ -1,16 +1,36 @@
 use crate::mmu::*;
+use crate::hdma::*;
 
-#[derive(Debug, Clone, Copy)]
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+// Line timing in m-cycles
+const LINE_M_CYCLES: u16 = 114;
+const OAM_SCAN_END: u16 = 20;
+const DRAWING_END: u16 = 63;
+
+// DMG shades as RGB555, white to black
+const DMG_SHADES: [u16; 4] = [0x7FFF, 0x56B5, 0x294A, 0x0000];
+
+#[derive(Debug, Clone)]
 pub struct Gpu {
     pub clocks: u16,
-    lcd_enabled: bool
+    lcd_enabled: bool,
+    window_line: u8,
+    // Finished frame as RGB555
+    pub framebuffer: Vec<u16>,
+    // Approximate the colours of the CGB LCD when converting to RGB888
+    pub color_correction: bool
 }
 
 impl Gpu {
     pub fn new() -> Gpu {
         Gpu {
             clocks: 0,
-            lcd_enabled: false
+            lcd_enabled: false,
+            window_line: 0,
+            framebuffer: vec![DMG_SHADES[0]; SCREEN_WIDTH * SCREEN_HEIGHT],
+            color_correction: false
         }
     }
 
@@ -18,24 +38,399 @@ impl Gpu {
         self.lcd_enabled = read_bit(0xFF40, 7, mem) == 1;
         if !self.lcd_enabled {
             mem[0xFF44] = 0;
+            self.clocks = 0;
+            set_mode(mem, 0);
             return;
         }
 
         self.clocks += 1;
-        if self.clocks % 114 == 0 {
-            let line_adr = 0xFF44 as usize;
-            //mem[line_adr] = (mem[line_adr] + 1) % 154;
-            mem[line_adr] += 1;
-            if mem[line_adr] > 153 {
-                mem[line_adr] = 0;
-                mem[0xFF0F] |= 0x01;
-                self.clocks -= 456;
+        let line_adr = 0xFF44;
+        let ly = mem[line_adr];
+
+        if (ly as usize) < SCREEN_HEIGHT {
+            match self.clocks {
+                1 => set_mode(mem, 2),
+                OAM_SCAN_END => set_mode(mem, 3),
+                DRAWING_END => {
+                    self.render_scanline(mem, ly);
+                    set_mode(mem, 0);
+                    hblank_dma(mem);
+                },
+                _ => {}
+            }
+        }
+
+        if self.clocks == LINE_M_CYCLES {
+            self.clocks = 0;
+            mem[line_adr] = (ly + 1) % 154;
+
+            match mem[line_adr] as usize {
+                // VBlank Interrupt
+                SCREEN_HEIGHT => {
+                    set_mode(mem, 1);
+                    mem[0xFF0F] |= 0x01;
+                },
+                0 => self.window_line = 0,
+                _ => {}
+            }
+        }
+    }
+
+    // Convert the framebuffer to 0RGB for display
+    pub fn to_rgb888(&self, buffer: &mut [u32]) {
+        for (out, color) in buffer.iter_mut().zip(self.framebuffer.iter()) {
+            *out = rgb555_to_rgb888(*color, self.color_correction);
+        }
+    }
+
+    fn render_scanline(&mut self, mem: &Memory, ly: u8) {
+        let lcdc = mem[0xFF40];
+        let scy = mem[0xFF42];
+        let scx = mem[0xFF43];
+        let wy = mem[0xFF4A];
+        let wx = mem[0xFF4B];
+
+        // On DMG bit 0 turns the background and window off
+        let bg_enabled = lcdc & 0x01 != 0 || mem.cgb_mode;
+        let window_enabled = lcdc & 0x20 != 0 && bg_enabled && ly >= wy && wx <= 166;
+
+        let line = ly as usize * SCREEN_WIDTH;
+        let mut bg_color_ids = [0u8; SCREEN_WIDTH];
+        // CGB tiles flagged to be drawn over sprites
+        let mut bg_priority = [false; SCREEN_WIDTH];
+
+        for (x, bg_color_id) in bg_color_ids.iter_mut().enumerate() {
+            if !bg_enabled {
+                self.framebuffer[line + x] = self.bg_color(mem, line + x, 0, 0);
+                continue;
+            }
+
+            let in_window = window_enabled && x + 7 >= wx as usize;
+            let (map_base, map_x, map_y) = match in_window {
+                true => (
+                    if lcdc & 0x40 != 0 { 0x9C00 } else { 0x9800 },
+                    (x + 7 - wx as usize) as u8,
+                    self.window_line
+                ),
+                false => (
+                    if lcdc & 0x08 != 0 { 0x9C00 } else { 0x9800 },
+                    (x as u8).wrapping_add(scx),
+                    ly.wrapping_add(scy)
+                )
+            };
+
+            let map_adr = map_base + (map_y as u16 / 8) * 32 + (map_x as u16 / 8);
+            let tile = vram_byte(mem, 0, map_adr);
+
+            // CGB attribute map sits in VRAM bank 1 at the same address
+            let attr = match mem.cgb_mode {
+                true => vram_byte(mem, 1, map_adr),
+                false => 0
+            };
+            let bank = ((attr >> 3) & 0x01) as usize;
+            let row = if attr & 0x40 != 0 { 7 - map_y % 8 } else { map_y % 8 };
+            let col = if attr & 0x20 != 0 { 7 - map_x % 8 } else { map_x % 8 };
+
+            let (lo, hi) = tile_row(mem, bank, tile_adr(lcdc, tile), row);
+            let color_id = color_id(lo, hi, col);
+
+            *bg_color_id = color_id;
+            bg_priority[x] = attr & 0x80 != 0;
+            self.framebuffer[line + x] = self.bg_color(mem, line + x, attr & 0x07, color_id);
+        }
+
+        if window_enabled {
+            self.window_line += 1;
+        }
+
+        if lcdc & 0x02 != 0 {
+            self.render_sprites(mem, ly, &bg_color_ids, &bg_priority);
+        }
+    }
+
+    fn render_sprites(&mut self, mem: &Memory, ly: u8, bg_color_ids: &[u8; SCREEN_WIDTH], bg_priority: &[bool; SCREEN_WIDTH]) {
+        let lcdc = mem[0xFF40];
+        let height = if lcdc & 0x04 != 0 { 16 } else { 8 };
+        let line = ly as usize * SCREEN_WIDTH;
+
+        // In CGB mode LCDC bit 0 clear puts sprites over everything
+        let bg_master_priority = !mem.cgb_mode || lcdc & 0x01 != 0;
+
+        // First 10 sprites on this line in OAM order
+        let mut sprites: Vec<(u8, u8, u8, u8)> = mem[0xFE00..0xFEA0].chunks_exact(4)
+            .map(|s| (s[0], s[1], s[2], s[3]))
+            .filter(|&(y, _, _, _)| {
+                let top = y as i16 - 16;
+                (ly as i16) >= top && (ly as i16) < top + height
+            })
+            .take(10)
+            .collect();
+
+        // With coordinate priority the sprite with the lower x wins, otherwise
+        // the first in OAM. DMG always uses coordinates, CGB picks through
+        // OPRI bit 0. Draw back to front.
+        if !mem.cgb_mode || mem[0xFF6C] & 0x01 != 0 {
+            sprites.sort_by_key(|&(_, x, _, _)| x);
+        }
+
+        for &(y, x, tile, attr) in sprites.iter().rev() {
+            let mut row = (ly as i16 - (y as i16 - 16)) as u8;
+            if attr & 0x40 != 0 {
+                row = height as u8 - 1 - row;
             }
+            let tile = if height == 16 { tile & 0xFE } else { tile };
+            let bank = match mem.cgb_mode {
+                true => ((attr >> 3) & 0x01) as usize,
+                false => 0
+            };
+            let (lo, hi) = tile_row(mem, bank, 0x8000 + tile as u16 * 16, row);
+
+            for col in 0..8u8 {
+                let screen_x = x as i16 - 8 + col as i16;
+                if !(0..SCREEN_WIDTH as i16).contains(&screen_x) {
+                    continue;
+                }
+                let screen_x = screen_x as usize;
+
+                let bit = if attr & 0x20 != 0 { 7 - col } else { col };
+                let color_id = color_id(lo, hi, bit);
+                if color_id == 0 {
+                    continue;
+                }
 
-            // VBlank Interrupt
-            if mem[line_adr] == 0 {
-                //mem[0xFF0F] |= 0x01;
+                // Behind background colours 1-3
+                let behind_bg = attr & 0x80 != 0 || bg_priority[screen_x];
+                if bg_master_priority && behind_bg && bg_color_ids[screen_x] != 0 {
+                    continue;
+                }
+
+                self.framebuffer[line + screen_x] = self.obj_color(mem, line + screen_x, attr, color_id);
             }
         }
     }
-}
\ No newline at end of file
+
+    // `pixel` is the framebuffer index, the SGB colours the screen by area
+    fn bg_color(&self, mem: &Memory, pixel: usize, palette: u8, color_id: u8) -> u16 {
+        match (mem.cgb_mode, mem.model.is_cgb()) {
+            (true, _) => palette_color(&mem.bg_palette, palette, color_id),
+            // DMG games on CGB hardware go through the compatibility palettes
+            (false, true) => palette_color(&mem.bg_palette, 0, shade(mem[0xFF47], color_id) as u8),
+            (false, false) => dmg_color(mem, pixel, shade(mem[0xFF47], color_id))
+        }
+    }
+
+    fn obj_color(&self, mem: &Memory, pixel: usize, attr: u8, color_id: u8) -> u16 {
+        if mem.cgb_mode {
+            return palette_color(&mem.obj_palette, attr & 0x07, color_id);
+        }
+
+        let (palette, obp) = if attr & 0x10 != 0 { (1, mem[0xFF49]) } else { (0, mem[0xFF48]) };
+        match mem.model.is_cgb() {
+            true => palette_color(&mem.obj_palette, palette, shade(obp, color_id) as u8),
+            false => dmg_color(mem, pixel, shade(obp, color_id))
+        }
+    }
+}
+
+impl Default for Gpu {
+    fn default() -> Gpu {
+        Gpu::new()
+    }
+}
+
+fn dmg_color(mem: &Memory, pixel: usize, shade: usize) -> u16 {
+    match &mem.sgb {
+        Some(sgb) => sgb.color(pixel, shade),
+        None => DMG_SHADES[shade]
+    }
+}
+
+fn set_mode(mem: &mut Memory, mode: u8) {
+    mem[0xFF41] = (mem[0xFF41] & 0xFC) | mode;
+}
+
+fn vram_byte(mem: &Memory, bank: usize, adr: u16) -> u8 {
+    mem.vram_bank(bank)[(adr - 0x8000) as usize]
+}
+
+// Tile data address, LCDC bit 4 selects between unsigned tile numbers from
+// 0x8000 and signed tile numbers from 0x9000
+pub fn tile_adr(lcdc: u8, tile: u8) -> u16 {
+    match lcdc & 0x10 != 0 {
+        true => 0x8000 + tile as u16 * 16,
+        false => (0x9000 + (tile as i8 as i32) * 16) as u16
+    }
+}
+
+fn tile_row(mem: &Memory, bank: usize, tile_adr: u16, row: u8) -> (u8, u8) {
+    let adr = tile_adr + row as u16 * 2;
+    (vram_byte(mem, bank, adr), vram_byte(mem, bank, adr + 1))
+}
+
+// Colour number of pixel `col` counting from the left
+fn color_id(lo: u8, hi: u8, col: u8) -> u8 {
+    let bit = 7 - col;
+    ((lo >> bit) & 0x01) | (((hi >> bit) & 0x01) << 1)
+}
+
+fn shade(palette: u8, color_id: u8) -> usize {
+    ((palette >> (color_id * 2)) & 0x03) as usize
+}
+
+// Colour from CGB palette RAM, 8 bytes per palette, little endian RGB555
+pub fn palette_color(ram: &[u8; 64], palette: u8, color_id: u8) -> u16 {
+    let i = palette as usize * 8 + color_id as usize * 2;
+    (ram[i] as u16 | (ram[i + 1] as u16) << 8) & 0x7FFF
+}
+
+pub fn rgb555_to_rgb888(color: u16, color_correction: bool) -> u32 {
+    let r = (color & 0x1F) as u32;
+    let g = ((color >> 5) & 0x1F) as u32;
+    let b = ((color >> 10) & 0x1F) as u32;
+
+    let (r, g, b) = match color_correction {
+        // Mix the channels like the CGB's LCD does
+        // https://byuu.net/video/color-emulation
+        true => (
+            (r * 26 + g * 4 + b * 2).min(960) >> 2,
+            (g * 24 + b * 8).min(960) >> 2,
+            (r * 6 + g * 4 + b * 22).min(960) >> 2
+        ),
+        false => ((r << 3) | (r >> 2), (g << 3) | (g >> 2), (b << 3) | (b >> 2))
+    };
+
+    (r << 16) | (g << 8) | b
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::*;
+
+    #[test]
+    fn test_rgb555_to_rgb888() {
+        assert_eq!(rgb555_to_rgb888(0x7FFF, false), 0xFFFFFF);
+        assert_eq!(rgb555_to_rgb888(0x001F, false), 0xFF0000);
+        assert_eq!(rgb555_to_rgb888(0x0000, true), 0x000000);
+        assert_eq!(rgb555_to_rgb888(0x7FFF, true), 0xF0F0F0);
+    }
+
+    #[test]
+    fn test_mode_timing() {
+        let mut mem = Memory::new();
+        let mut gpu = Gpu::new();
+        mem[0xFF40] = 0x80;
+
+        for _ in 0..OAM_SCAN_END {
+            gpu.tick(&mut mem);
+        }
+        assert_eq!(mem[0xFF41] & 0x03, 3);
+        for _ in OAM_SCAN_END..DRAWING_END {
+            gpu.tick(&mut mem);
+        }
+        assert_eq!(mem[0xFF41] & 0x03, 0);
+
+        while mem[0xFF44] != 144 {
+            gpu.tick(&mut mem);
+        }
+        assert_eq!(mem[0xFF41] & 0x03, 1);
+        assert_eq!(mem[0xFF0F] & 0x01, 0x01);
+    }
+
+    #[test]
+    fn test_cgb_background_palette() {
+        let mut mem = Memory::with_model(Model::Cgb, true);
+        let mut gpu = Gpu::new();
+        mem[0xFF40] = 0x91;
+
+        // Tile 0 row 0 is colour 3, palette 0 colour 3 is pure red
+        mem[0x8000] = 0xFF;
+        mem[0x8001] = 0xFF;
+        write_byte(0xFF68, 0x86, &mut mem);
+        write_byte(0xFF69, 0x1F, &mut mem);
+        write_byte(0xFF69, 0x00, &mut mem);
+
+        for _ in 0..DRAWING_END {
+            gpu.tick(&mut mem);
+        }
+        assert_eq!(gpu.framebuffer[0], 0x001F);
+    }
+
+    #[test]
+    fn test_dmg_compat_palette() {
+        let mut mem = Memory::with_model(Model::Cgb, false);
+        let mut gpu = Gpu::new();
+        mem[0xFF40] = 0x91;
+        // Colour 3 maps to shade 1
+        mem[0xFF47] = 0x40;
+        mem[0x8000] = 0xFF;
+        mem[0x8001] = 0xFF;
+
+        // Palette RAM holds the compatibility palette, the registers are locked
+        mem.bg_palette[2] = 0x1F;
+        mem.bg_palette[3] = 0x00;
+
+        for _ in 0..DRAWING_END {
+            gpu.tick(&mut mem);
+        }
+        assert_eq!(gpu.framebuffer[0], 0x001F);
+    }
+
+    #[test]
+    fn test_cgb_background_attributes() {
+        let mut mem = Memory::with_model(Model::Cgb, true);
+        let mut gpu = Gpu::new();
+        mem[0xFF40] = 0x91;
+
+        // Tile 0 in bank 1, row 0 has only its leftmost pixel set
+        write_byte(0xFF4F, 0x01, &mut mem);
+        mem[0x8000] = 0x80;
+        // Palette 2, tile bank 1, X flip
+        mem[0x9800] = 0x2A;
+        write_byte(0xFF4F, 0x00, &mut mem);
+
+        // Palette 2 colour 1 is pure blue
+        write_byte(0xFF68, 0x80 | (2 * 8 + 2), &mut mem);
+        write_byte(0xFF69, 0x00, &mut mem);
+        write_byte(0xFF69, 0x7C, &mut mem);
+
+        for _ in 0..DRAWING_END {
+            gpu.tick(&mut mem);
+        }
+        assert_eq!(gpu.framebuffer[7], 0x7C00);
+        assert_eq!(gpu.framebuffer[0], palette_color(&mem.bg_palette, 2, 0));
+    }
+
+    #[test]
+    fn test_cgb_sprite_priority() {
+        let mut mem = Memory::with_model(Model::Cgb, true);
+        let mut gpu = Gpu::new();
+
+        // Background tile 0 fully colour 1 with the BG priority attribute
+        mem[0x8000] = 0xFF;
+        write_byte(0xFF4F, 0x01, &mut mem);
+        mem[0x9800] = 0x80;
+        write_byte(0xFF4F, 0x00, &mut mem);
+        // Sprite using tile 1, colour 3, palette 1
+        mem[0x8010] = 0xFF;
+        mem[0x8011] = 0xFF;
+        mem[0xFE00..0xFE04].copy_from_slice(&[16, 8, 1, 0x01]);
+        write_byte(0xFF6A, 0x80 | (8 + 6), &mut mem);
+        write_byte(0xFF6B, 0xE0, &mut mem);
+        write_byte(0xFF6B, 0x03, &mut mem);
+
+        // Background wins while LCDC bit 0 is set
+        mem[0xFF40] = 0x93;
+        for _ in 0..LINE_M_CYCLES {
+            gpu.tick(&mut mem);
+        }
+        assert_ne!(gpu.framebuffer[0], 0x03E0);
+
+        // Sprites win everywhere when it's clear
+        mem[0xFF40] = 0x92;
+        for _ in 0..(LINE_M_CYCLES * 154) {
+            gpu.tick(&mut mem);
+        }
+        assert_eq!(gpu.framebuffer[0], 0x03E0);
+    }
+}
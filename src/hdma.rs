@@ -0,0 +1,167 @@
+// CGB VRAM DMA through HDMA1-HDMA5 (0xFF51-0xFF55)
+//
+// General purpose DMA copies everything at once and halts the CPU meanwhile.
+// HBlank DMA copies 16 bytes at the start of every HBlank, HDMA5 reads back
+// the number of blocks left minus one with bit 7 clear while it's active.
+
+use crate::mmu::*;
+
+// CPU is halted for 8 m-cycles per 16 byte block at normal speed
+const BLOCK_M_CYCLES: u16 = 8;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Hdma {
+    pub src: u16,
+    // Offset into VRAM
+    pub dst: u16,
+    // Blocks left minus one
+    remaining: u8,
+    active: bool
+}
+
+impl Hdma {
+    pub fn new() -> Hdma {
+        Hdma {
+            src: 0,
+            dst: 0,
+            remaining: 0,
+            active: false
+        }
+    }
+}
+
+impl Default for Hdma {
+    fn default() -> Hdma {
+        Hdma::new()
+    }
+}
+
+// HDMA5 write starts a transfer, or cancels a running HBlank DMA
+pub fn start_hdma(val: u8, mem: &mut Memory) {
+    if mem.hdma.active {
+        if val & 0x80 == 0 {
+            mem.hdma.active = false;
+            mem[0xFF55] = 0x80 | mem.hdma.remaining;
+        }
+        return;
+    }
+
+    mem.hdma.remaining = val & 0x7F;
+    if val & 0x80 == 0 {
+        // General purpose DMA
+        let blocks = mem.hdma.remaining as u16 + 1;
+        for _ in 0..blocks {
+            copy_block(mem);
+        }
+        mem.dma_stall += blocks * block_cycles(mem);
+        mem[0xFF55] = 0xFF;
+    } else {
+        mem.hdma.active = true;
+        mem[0xFF55] = mem.hdma.remaining;
+
+        // With the LCD off there's no HBlank to wait for
+        if mem[0xFF40] & 0x80 == 0 {
+            hblank_dma(mem);
+        }
+    }
+}
+
+// Called by the PPU when entering HBlank
+pub fn hblank_dma(mem: &mut Memory) {
+    if !mem.hdma.active {
+        return;
+    }
+
+    copy_block(mem);
+    mem.dma_stall += block_cycles(mem);
+
+    if mem.hdma.remaining == 0 {
+        mem.hdma.active = false;
+        mem[0xFF55] = 0xFF;
+    } else {
+        mem.hdma.remaining -= 1;
+        mem[0xFF55] = mem.hdma.remaining;
+    }
+}
+
+fn block_cycles(mem: &Memory) -> u16 {
+    match mem.double_speed {
+        true => BLOCK_M_CYCLES * 2,
+        false => BLOCK_M_CYCLES
+    }
+}
+
+fn copy_block(mem: &mut Memory) {
+    for _ in 0..16 {
+        let val = read_byte(mem.hdma.src, mem);
+        let dst = 0x8000 | (mem.hdma.dst & 0x1FFF);
+        write_byte(dst, val, mem);
+        mem.hdma.src = mem.hdma.src.wrapping_add(1);
+        mem.hdma.dst = mem.hdma.dst.wrapping_add(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::*;
+
+    fn setup(src: u16, dst: u16) -> Memory {
+        let mut mem = Memory::with_model(Model::Cgb, true);
+        for i in 0..0x100 {
+            mem[(src + i) as usize] = i as u8;
+        }
+        write_byte(0xFF51, (src >> 8) as u8, &mut mem);
+        write_byte(0xFF52, src as u8, &mut mem);
+        write_byte(0xFF53, (dst >> 8) as u8, &mut mem);
+        write_byte(0xFF54, dst as u8, &mut mem);
+        mem
+    }
+
+    #[test]
+    fn test_general_purpose_dma() {
+        let mut mem = setup(0xC000, 0x8800);
+        write_byte(0xFF55, 0x01, &mut mem);
+
+        assert_eq!(&mem[0x8800..0x8820], &mem[0xC000..0xC020]);
+        assert_eq!(mem[0x8820], 0x00);
+        assert_eq!(read_byte(0xFF55, &mem), 0xFF);
+        assert_eq!(mem.dma_stall, 16);
+    }
+
+    #[test]
+    fn test_hblank_dma() {
+        let mut mem = setup(0xC000, 0x8000);
+        mem[0xFF40] = 0x80;
+        write_byte(0xFF55, 0x81, &mut mem);
+        assert_eq!(read_byte(0xFF55, &mem), 0x01);
+        assert_eq!(mem[0x8000], 0x00);
+        assert_eq!(mem[0x8001], 0x00);
+
+        hblank_dma(&mut mem);
+        assert_eq!(&mem[0x8000..0x8010], &mem[0xC000..0xC010]);
+        assert_eq!(mem[0x8010], 0x00);
+        assert_eq!(read_byte(0xFF55, &mem), 0x00);
+
+        hblank_dma(&mut mem);
+        assert_eq!(&mem[0x8010..0x8020], &mem[0xC010..0xC020]);
+        assert_eq!(read_byte(0xFF55, &mem), 0xFF);
+
+        // Nothing left to do
+        hblank_dma(&mut mem);
+        assert_eq!(mem[0x8020], 0x00);
+    }
+
+    #[test]
+    fn test_cancel_hblank_dma() {
+        let mut mem = setup(0xC000, 0x8000);
+        mem[0xFF40] = 0x80;
+        write_byte(0xFF55, 0x83, &mut mem);
+        hblank_dma(&mut mem);
+        write_byte(0xFF55, 0x00, &mut mem);
+        assert_eq!(read_byte(0xFF55, &mem), 0x82);
+
+        hblank_dma(&mut mem);
+        assert_eq!(mem[0x8011], 0x00);
+    }
+}
@@ -0,0 +1,190 @@
+// CGB infrared port, RP (0xFF56).
+//
+//   bit 0    LED, 1 = emitting
+//   bit 1    receiver, 0 = light detected, reads 1 unless reading is enabled
+//   bits 6-7 both set to enable reading
+//
+// Games time the light pulses themselves, so the peers only exchange the LED
+// state. Either in-process through a shared flag per direction or over UDP,
+// one byte per change.
+
+use crate::mmu::*;
+use crate::gameboy::*;
+
+use std::cell::Cell;
+use std::io;
+use std::net::{ ToSocketAddrs, UdpSocket };
+use std::rc::Rc;
+
+// Only hit the socket every so often
+const POLL_INTERVAL: u16 = 32;
+
+pub trait IrLink {
+    fn set_led(&mut self, on: bool);
+    // Light seen from the other side
+    fn light(&mut self) -> bool;
+}
+
+// One end of an in-process IR connection
+pub struct LocalIr {
+    tx: Rc<Cell<bool>>,
+    rx: Rc<Cell<bool>>
+}
+
+impl IrLink for LocalIr {
+    fn set_led(&mut self, on: bool) {
+        self.tx.set(on);
+    }
+
+    fn light(&mut self) -> bool {
+        self.rx.get()
+    }
+}
+
+// Two IR ports facing each other
+pub fn local_ir_pair() -> (LocalIr, LocalIr) {
+    let a = Rc::new(Cell::new(false));
+    let b = Rc::new(Cell::new(false));
+    (
+        LocalIr { tx: a.clone(), rx: b.clone() },
+        LocalIr { tx: b, rx: a }
+    )
+}
+
+// IR connection to another rustboy process
+pub struct UdpIr {
+    socket: UdpSocket,
+    light: bool,
+    polls: u16
+}
+
+impl UdpIr {
+    pub fn bind<A: ToSocketAddrs, B: ToSocketAddrs>(local: A, peer: B) -> io::Result<UdpIr> {
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(peer)?;
+        socket.set_nonblocking(true)?;
+        Ok(UdpIr { socket, light: false, polls: 0 })
+    }
+}
+
+impl IrLink for UdpIr {
+    fn set_led(&mut self, on: bool) {
+        // Lost or refused packets just mean nobody is looking
+        let _ = self.socket.send(&[on as u8]);
+    }
+
+    fn light(&mut self) -> bool {
+        self.polls = (self.polls + 1) % POLL_INTERVAL;
+        if self.polls != 0 {
+            return self.light;
+        }
+
+        // Drain everything that arrived, the last state wins. Errors include
+        // WouldBlock once empty and a refused port while the peer is away.
+        let mut buf = [0; 1];
+        while let Ok(len) = self.socket.recv(&mut buf) {
+            if len == 1 {
+                self.light = buf[0] != 0;
+            }
+        }
+        self.light
+    }
+}
+
+pub struct Infrared {
+    link: Option<Box<dyn IrLink>>,
+    led: bool
+}
+
+impl Infrared {
+    pub fn new() -> Infrared {
+        Infrared { link: None, led: false }
+    }
+
+    pub fn connect(&mut self, link: Box<dyn IrLink>) {
+        self.link = Some(link);
+    }
+
+    pub fn tick(&mut self, mem: &mut Memory) {
+        if !mem.cgb_mode {
+            return;
+        }
+
+        let rp = mem[0xFF56];
+        let Some(link) = self.link.as_mut() else {
+            mem[0xFF56] = rp | 0x02;
+            return;
+        };
+
+        let led = rp & 0x01 != 0;
+        if led != self.led {
+            self.led = led;
+            link.set_led(led);
+        }
+
+        let receiving = rp & 0xC0 == 0xC0 && link.light();
+        mem[0xFF56] = match receiving {
+            true => rp & !0x02,
+            false => rp | 0x02
+        };
+    }
+}
+
+impl Default for Infrared {
+    fn default() -> Infrared {
+        Infrared::new()
+    }
+}
+
+// Point the IR ports of two Game Boys at each other
+pub fn connect_ir(a: &mut GameBoy, b: &mut GameBoy) {
+    let (ir_a, ir_b) = local_ir_pair();
+    a.infrared.connect(Box::new(ir_a));
+    b.infrared.connect(Box::new(ir_b));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::*;
+
+    #[test]
+    fn test_local_ir() {
+        let mut a = Memory::with_model(Model::Cgb, true);
+        let mut b = Memory::with_model(Model::Cgb, true);
+        let (ir_a, ir_b) = local_ir_pair();
+        let mut port_a = Infrared::new();
+        let mut port_b = Infrared::new();
+        port_a.connect(Box::new(ir_a));
+        port_b.connect(Box::new(ir_b));
+
+        write_byte(0xFF56, 0x01, &mut a);
+        write_byte(0xFF56, 0x00, &mut b);
+        port_a.tick(&mut a);
+        port_b.tick(&mut b);
+        // Reading disabled
+        assert_eq!(read_byte(0xFF56, &b), 0x3E);
+
+        write_byte(0xFF56, 0xC0, &mut b);
+        port_b.tick(&mut b);
+        assert_eq!(read_byte(0xFF56, &b), 0xFC);
+
+        write_byte(0xFF56, 0x00, &mut a);
+        port_a.tick(&mut a);
+        port_b.tick(&mut b);
+        assert_eq!(read_byte(0xFF56, &b), 0xFE);
+    }
+
+    #[test]
+    fn test_udp_ir() {
+        let mut b = UdpIr::bind("127.0.0.1:0", "127.0.0.1:9").unwrap();
+        let b_addr = b.socket.local_addr().unwrap();
+        let mut a = UdpIr::bind("127.0.0.1:0", b_addr).unwrap();
+        b.socket.connect(a.socket.local_addr().unwrap()).unwrap();
+
+        a.set_led(true);
+        let start = std::time::Instant::now();
+        while !b.light() && start.elapsed().as_secs() < 1 {}
+        assert!(b.light());
+    }
+}
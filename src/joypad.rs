@@ -0,0 +1,106 @@
+use crate::mmu::*;
+use crate::sgb::*;
+
+// Buttons in the order of their P1 lines, directions are selected by P14
+// and the others by P15
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start
+}
+
+// Pressed buttons, one byte per controller. Only the SGB can poll more than
+// the first through MLT_REQ.
+pub struct Joypad {
+    pressed: [u8; 4]
+}
+
+impl Joypad {
+    pub fn new() -> Joypad {
+        Joypad { pressed: [0; 4] }
+    }
+}
+
+impl Default for Joypad {
+    fn default() -> Joypad {
+        Joypad::new()
+    }
+}
+
+pub fn set_button(mem: &mut Memory, player: usize, button: Button, pressed: bool) {
+    let bit = 1 << button as u8;
+    let was_pressed = mem.joypad.pressed[player] & bit != 0;
+
+    match pressed {
+        true => mem.joypad.pressed[player] |= bit,
+        false => mem.joypad.pressed[player] &= !bit
+    }
+
+    // Joypad interrupt when a line goes low
+    if pressed && !was_pressed {
+        mem[0xFF0F] |= 0x10;
+    }
+}
+
+// P1 - the low nibble is active low for the selected button groups. With
+// neither group selected the SGB answers the current controller number.
+pub fn read_joypad(mem: &Memory) -> u8 {
+    let p1 = mem[0xFF00] & 0x30;
+    let player = mem.sgb.as_ref().map_or(0, |sgb| sgb.player());
+    let pressed = mem.joypad.pressed[player];
+
+    let mut lines = 0x0F;
+    if p1 == 0x30 {
+        lines -= player as u8;
+    }
+    if p1 & 0x10 == 0 {
+        lines &= !pressed & 0x0F;
+    }
+    if p1 & 0x20 == 0 {
+        lines &= !(pressed >> 4) & 0x0F;
+    }
+    0xC0 | p1 | lines
+}
+
+// Only the select lines are writable, writes also carry SGB packets
+pub fn write_joypad(val: u8, mem: &mut Memory) {
+    let old = mem[0xFF00] & 0x30;
+    mem[0xFF00] = 0xC0 | (val & 0x30) | 0x0F;
+
+    if mem.sgb.is_some() {
+        write_sgb(old, val & 0x30, mem);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_joypad() {
+        let mut mem = Memory::new();
+        write_joypad(0x30, &mut mem);
+        assert_eq!(read_joypad(&mem), 0xFF);
+
+        set_button(&mut mem, 0, Button::Down, true);
+        set_button(&mut mem, 0, Button::A, true);
+        assert_eq!(mem[0xFF0F] & 0x10, 0x10);
+
+        write_joypad(0x20, &mut mem);
+        assert_eq!(read_joypad(&mem), 0xE7);
+        write_joypad(0x10, &mut mem);
+        assert_eq!(read_joypad(&mem), 0xDE);
+        write_joypad(0x00, &mut mem);
+        assert_eq!(read_joypad(&mem), 0xC6);
+
+        set_button(&mut mem, 0, Button::Down, false);
+        write_joypad(0x20, &mut mem);
+        assert_eq!(read_joypad(&mem), 0xEF);
+    }
+}
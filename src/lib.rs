@@ -0,0 +1,33 @@
+// rustboy, a Game Boy emulator. GameBoy is the place to start, the modules
+// stay public for frontends that need to reach further in.
+
+pub mod registers;
+pub mod alu;
+pub mod mmu;
+pub mod cpu;
+pub mod gpu;
+pub mod cb;
+pub mod serial;
+pub mod gameboy;
+pub mod link;
+pub mod tcp_link;
+pub mod four_player;
+pub mod cartridge;
+pub mod model;
+pub mod timer;
+pub mod hdma;
+pub mod compat_palette;
+pub mod joypad;
+pub mod sgb;
+pub mod infrared;
+pub mod apu;
+pub mod mixer;
+pub mod audio_sink;
+#[cfg(feature = "device-audio")]
+pub mod device_sink;
+pub mod pacing;
+pub mod vgm;
+pub mod gbs;
+
+pub use crate::gameboy::GameBoy;
+pub use crate::joypad::Button;
@@ -0,0 +1,102 @@
+use crate::gameboy::*;
+use crate::serial::*;
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+type Queue = Rc<RefCell<VecDeque<LinkMessage>>>;
+
+// One end of an in-process link cable
+pub struct LocalLink {
+    tx: Queue,
+    rx: Queue
+}
+
+impl SerialLink for LocalLink {
+    fn send(&mut self, msg: LinkMessage) {
+        self.tx.borrow_mut().push_back(msg);
+    }
+
+    fn recv(&mut self) -> Option<LinkMessage> {
+        self.rx.borrow_mut().pop_front()
+    }
+}
+
+// Both ends of a link cable, cross-wired
+pub fn local_link_pair() -> (LocalLink, LocalLink) {
+    let a: Queue = Rc::new(RefCell::new(VecDeque::new()));
+    let b: Queue = Rc::new(RefCell::new(VecDeque::new()));
+    (
+        LocalLink { tx: a.clone(), rx: b.clone() },
+        LocalLink { tx: b, rx: a }
+    )
+}
+
+// Plug a link cable into two Game Boys
+pub fn connect(a: &mut GameBoy, b: &mut GameBoy) {
+    let (link_a, link_b) = local_link_pair();
+    a.serial.connect(Box::new(link_a));
+    b.serial.connect(Box::new(link_b));
+}
+
+// Run one instruction on `a`, then catch `b` up so both stay within an
+// instruction of each other. Returns the m-cycle length of `a`'s instruction.
+pub fn step_linked(a: &mut GameBoy, b: &mut GameBoy) -> u16 {
+    let op_cycles = a.step_instruction();
+    while b.cycles < a.cycles {
+        b.step_instruction();
+    }
+    op_cycles
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Sends `out` over the link, then stores the received byte at 0xFF80
+    fn transfer_rom(out: u8, sc: u8) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        let program = [
+            0x3E, out,  // LD A, out
+            0xE0, 0x01, // LDH (SB), A
+            0x3E, sc,   // LD A, sc
+            0xE0, 0x02, // LDH (SC), A
+            0xF0, 0x02, // LDH A, (SC)
+            0xE6, 0x80, // AND 0x80
+            0x20, 0xFA, // JR NZ, -6
+            0xF0, 0x01, // LDH A, (SB)
+            0xE0, 0x80, // LDH (0xFF80), A
+            0x18, 0xFE  // JR -2
+        ];
+        rom[0x0100..0x0100 + program.len()].copy_from_slice(&program);
+        rom
+    }
+
+    #[test]
+    fn test_linked_transfer() {
+        let mut master = GameBoy::new(&transfer_rom(0x42, 0x81));
+        let mut slave = GameBoy::new(&transfer_rom(0x99, 0x80));
+        connect(&mut master, &mut slave);
+
+        while master.cycles < 4096 {
+            step_linked(&mut master, &mut slave);
+        }
+
+        assert_eq!(master.mem[0xFF80], 0x99);
+        assert_eq!(slave.mem[0xFF80], 0x42);
+        assert_eq!(master.serial.output(), &[0x42]);
+        assert_eq!(slave.serial.output(), &[0x99]);
+    }
+
+    #[test]
+    fn test_unlinked_master_reads_ones() {
+        let mut master = GameBoy::new(&transfer_rom(0x42, 0x81));
+
+        while master.cycles < 4096 {
+            master.step_instruction();
+        }
+
+        assert_eq!(master.mem[0xFF80], 0xFF);
+    }
+}
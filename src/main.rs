@@ -1,129 +1,295 @@
-mod registers;
-mod alu;
-mod mmu;
-mod cpu;
-mod gpu;
-mod cb;
-
-use crate::mmu::*;
-use crate::cpu::*;
-use crate::gpu::*;
-
-use minifb::{ Window, WindowOptions };
-
-use std::io::stdin;
-use std::io::{ Read, Write };
-use std::ops::Mul;
-use std::path::Path;
-use std::fs::File;
+use rustboy::GameBoy;
+use rustboy::link::*;
+use rustboy::tcp_link::*;
+use rustboy::four_player::*;
+use rustboy::compat_palette::*;
+use rustboy::joypad::*;
+use rustboy::infrared::*;
+use rustboy::audio_sink::*;
+#[cfg(feature = "device-audio")]
+use rustboy::device_sink::*;
+use rustboy::apu::M_CYCLES_PER_SECOND;
+use rustboy::gameboy::run_frame_linked;
+use rustboy::pacing::*;
+use rustboy::gbs::*;
+
+mod cli;
+
+use crate::cli::*;
+
+use minifb::{ Key, KeyRepeat, Scale, Window, WindowOptions };
+
+use std::io::Write;
+use std::process::ExitCode;
 use std::time::Duration;
 use log::debug;
-use std::thread;
-
-const WIDTH: usize = 160;
-const HEIGHT: usize = 144;
-const LIMIT_CYCLES: bool = true;
-
-fn main() {
-    env_logger::builder()
-        .format(|buf, record| {
-            match record.level() {
-                log::Level::Debug => writeln!(buf, "{}", record.args()),
-                _ => writeln!(buf, "{}: {}", record.level(), record.args()),
-                
-            }
-        }).init();
-
-    println!("Hello, rustboy!");
-
-    let mut mem: Memory = [0; 0xFFFF + 1];
-    let mut cpu: Cpu = Cpu::new();
-    let mut gpu = Gpu::new();
-    let mut window = Window::new("rustboy", WIDTH, HEIGHT, WindowOptions::default()).unwrap();
-    let mut buffer: Vec<u32> = vec![255; WIDTH * HEIGHT];
-    
-    init_memory(&mut mem);
-    
-    // Open the path in read-only mode
-    let path = Path::new("tetris.gb");
-    let display = path.display();
-    let mut file = match File::open(&path) {
-        Err(why) => panic!("couldn't open {}: {}", display, why),
-        Ok(file) => file,
+
+const KEYS: [(Key, Button); 8] = [
+    (Key::Right, Button::Right), (Key::Left, Button::Left), (Key::Up, Button::Up),
+    (Key::Down, Button::Down), (Key::X, Button::A), (Key::Z, Button::B),
+    (Key::Backspace, Button::Select), (Key::Enter, Button::Start)
+];
+
+// Toggle mute of APU channel 1-4, or solo with shift held
+const CHANNEL_KEYS: [Key; 4] = [Key::Key1, Key::Key2, Key::Key3, Key::Key4];
+
+// Render a track of a GBS file to WAV
+fn play_gbs(data: &[u8], options: &Options) -> Result<(), String> {
+    let mut player = GbsPlayer::new(data)?;
+    let header = &player.header;
+    println!("{} - {} ({})", header.title, header.author, header.copyright);
+    println!("{} songs, playing on {}", header.songs, if header.uses_timer() { "timer" } else { "VBlank" });
+
+    let track = options.track.unwrap_or(header.first_song.max(1));
+    if !(1..=header.songs).contains(&track) {
+        return Err(format!("invalid track {}, expected 1-{}", track, header.songs));
+    }
+    let seconds = options.seconds.unwrap_or(150.0);
+
+    let Some(wav_path) = options.wav.as_ref() else {
+        println!("No audio output, export with --wav PATH");
+        return Ok(());
     };
-    
-    // Copy rom data to memory
-    match file.read(&mut mem) {
-        Err(why) => panic!("couldn't read {}: {}", display, why),
-        Ok(_) => { 
-            print!("{} loaded!\n\n", display)
+    let wav = WavWriter::create(wav_path, options.sample_rate)
+        .map_err(|why| format!("couldn't create {}: {}", wav_path.display(), why))?;
+    player.gb.mem.apu.set_sample_rate(options.sample_rate);
+    player.gb.mem.apu.set_sink(Box::new(wav));
+
+    println!("Writing track {} to {}...", track, wav_path.display());
+    player.start_song(track - 1);
+    player.run((seconds * M_CYCLES_PER_SECOND as f64) as u64)
+}
+
+fn main() -> ExitCode {
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) if options.help => {
+            println!("{}", USAGE);
+            return ExitCode::SUCCESS;
+        },
+        Ok(options) => options,
+        Err(why) => {
+            eprintln!("error: {}\n\n{}", why, USAGE);
+            return ExitCode::from(2);
         }
+    };
+
+    let mut logger = env_logger::builder();
+    if let Some(level) = options.log_level {
+        logger.filter_level(level);
     }
-    
-    assert_eq!(read_byte(0x0147, &mem), 0x00, "MBC not supported!");
-    
-    const M_CYCLES_PER_FRAME: u32 = 16384;
-    //const M_CYCLE_DUR: Duration = Duration::from_secs_f64(4.0 / 4194304.0 as f64); // unstable
-    const M_CYCLE_DUR: Duration = Duration::from_nanos(954); // 953.67431640625
-    let mut call_count = 0;
-    let mut frame_cur_m_cycles = 0;
-    loop {
-        call_count += 1;
-        debug!("IME: {}", cpu.reg.ime);
-
-        // Run next instruction
-        let now = std::time::Instant::now();
-        let op_cycles = cpu.tick(&mut mem);
-        for _cycle in 0..op_cycles {
-            // Run CPU m-cycle
-            gpu.tick(&mut mem);
-            frame_cur_m_cycles += 1;
-            if frame_cur_m_cycles == M_CYCLES_PER_FRAME {
-                window.update_with_buffer(&buffer, WIDTH, HEIGHT).unwrap(); 
-                frame_cur_m_cycles = 0;
-            }
+    logger.format(|buf, record| {
+        match record.level() {
+            log::Level::Debug => writeln!(buf, "{}", record.args()),
+            _ => writeln!(buf, "{}: {}", record.level(), record.args()),
         }
-        let elapsed = now.elapsed();
-        let op_duration = M_CYCLE_DUR.mul(op_cycles as u32);
-        if elapsed < op_duration && LIMIT_CYCLES {
-            thread::sleep(op_duration - elapsed);
+    }).init();
+
+    match run(&options) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(why) => {
+            eprintln!("error: {}", why);
+            ExitCode::FAILURE
         }
+    }
+}
+
+fn run(options: &Options) -> Result<(), String> {
+    let display = options.rom.display();
+    let rom = std::fs::read(&options.rom).map_err(|why| format!("couldn't read {}: {}", display, why))?;
+    if rom.starts_with(b"GBS") {
+        return play_gbs(&rom, options).map_err(|why| format!("{}: {}", display, why));
+    }
+    print!("{} loaded!\n\n", display);
+
+    let gb = GameBoy::from_rom_bytes(&rom).map_err(|why| format!("{}: {}", display, why))?;
+
+    // Hardware model, picked from the cartridge header unless given
+    let model = options.model.unwrap_or(gb.mem.model);
+    println!("Running as {}", model);
+
+    // Every instance starts the same way, from the boot rom if there is one
+    let boot_rom = match options.boot_rom.as_ref() {
+        Some(path) => Some(std::fs::read(path).map_err(|why| format!("couldn't read {}: {}", path.display(), why))?),
+        None => None
+    };
+    let new_instance = || match boot_rom.as_ref() {
+        Some(boot_rom) => GameBoy::with_boot_rom(&rom, model, boot_rom),
+        None => Ok(GameBoy::with_model(&rom, model))
+    };
+
+    // The first player is the one on screen, any others are driven headless
+    let first = match (boot_rom.as_ref(), model == gb.mem.model) {
+        (None, true) => gb,
+        _ => new_instance()?
+    };
+    let mut players = vec![first];
+    players[0].serial.echo_stdout = options.serial_stdout;
+    players[0].gpu.color_correction = options.color_correction;
 
-        // Debug
-        debug!("Call count: {}", call_count);
-        debug!("Line Y: {}", read_byte(0xFF44, &mem));
-        cpu.reg.debug();
-        debug!("\n");
+    // Override the colours picked for DMG games on CGB, like holding a button combo during boot
+    if let Some(combo) = options.palette {
+        if !model.is_cgb() || players[0].mem.cgb_mode {
+            return Err("--palette only applies to DMG games running on a CGB".to_string());
+        }
+        load_compat_palette(&mut players[0].mem, combo.palette());
+    }
 
-        if call_count > 80000 {
-            break;
+    // The SGB draws its border around the screen
+    let (width, height) = players[0].screen_size();
+    let mut window = match options.headless {
+        true => None,
+        false => {
+            let scale = match options.scale {
+                1 => Scale::X1,
+                2 => Scale::X2,
+                4 => Scale::X4,
+                _ => Scale::X8
+            };
+            let window_options = WindowOptions { scale, ..WindowOptions::default() };
+            Some(Window::new("rustboy", width, height, window_options).map_err(|why| format!("couldn't open window: {}", why))?)
         }
+    };
+    let mut buffer: Vec<u32> = vec![255; width * height];
+
+    // Second instance running the same rom, wired up through a link cable
+    if options.link {
+        let mut partner = new_instance()?;
+        connect(&mut players[0], &mut partner);
+        players.push(partner);
     }
 
-    // Hacky print of vram tile data
-    // TODO: Remove this
-    println!("{:?}", &mem[0x8000..0x8800]);
-    let mut tile_row = 0;
-    let tile_data = &mem[0x8000..0x8800];
-    tile_data.chunks_exact(16).enumerate().for_each(|(i, tile)| {
-        if i % 20 == 0 && i != 0 { tile_row += 1 }
-        tile.chunks_exact(2).enumerate().for_each(|(j, row)| {
-            for col in 0..7 {
-                let pixel = (row[0] >> (7 - col) & 0x1) + ((row[1] >> (7 - col) & 0x1));
-                buffer[(tile_row * WIDTH * 8) + ((j * WIDTH) + (i * 8 + col)) as usize] = match pixel {
-                    0 => 0xFF0000FF,
-                    1 => 0xFF00FF00,
-                    2 => 0xFFFF0000,
-                    3 => 0xFFFFFFFF,
-                    _ => 0xFF000000,
-                };
+    // Second instance with the IR ports facing each other
+    if options.ir {
+        if players.len() < 2 {
+            players.push(new_instance()?);
+        }
+        let (gb, partner) = players.split_at_mut(1);
+        connect_ir(&mut gb[0], &mut partner[0]);
+    }
+
+    // Four instances running the same rom, wired up through a DMG-07
+    let mut adapter = if options.four_player {
+        let mut adapter = FourPlayerAdapter::new();
+        while players.len() < 4 {
+            players.push(new_instance()?);
+        }
+        players.iter_mut().for_each(|gb| adapter.connect(gb));
+        Some(adapter)
+    } else {
+        None
+    };
+
+    // Link cable to another rustboy process
+    if let Some(addr) = options.link_listen.as_ref() {
+        println!("Waiting for link partner on {}...", addr);
+        let link = TcpLink::listen(addr).map_err(|why| format!("couldn't listen on {}: {}", addr, why))?;
+        players[0].serial.connect(Box::new(link));
+    } else if let Some(addr) = options.link_connect.as_ref() {
+        let link = TcpLink::connect(addr).map_err(|why| format!("couldn't connect to {}: {}", addr, why))?;
+        players[0].serial.connect(Box::new(link));
+    }
+
+    // IR port facing another rustboy process
+    if let Some((local, peer)) = options.ir_udp.as_ref() {
+        let ir = UdpIr::bind(local, peer).map_err(|why| format!("couldn't set up IR on {}: {}", local, why))?;
+        players[0].infrared.connect(Box::new(ir));
+    }
+
+    for gb in players.iter_mut() {
+        gb.mem.apu.set_sample_rate(options.sample_rate);
+        gb.mem.apu.set_sink(Box::new(NullSink));
+    }
+
+    // Record the first player's audio
+    if let Some(path) = options.wav.as_ref() {
+        let wav = WavWriter::create(path, options.sample_rate)
+            .map_err(|why| format!("couldn't create {}: {}", path.display(), why))?;
+        players[0].mem.apu.set_sink(Box::new(wav));
+    }
+
+    // Or play it along with the window
+    #[cfg(feature = "device-audio")]
+    if options.wav.is_none() && window.is_some() && !options.mute {
+        match DeviceSink::open(options.sample_rate) {
+            Ok(device) => players[0].mem.apu.set_sink(Box::new(device)),
+            Err(why) => log::warn!("no sound, couldn't open audio output: {}", why)
+        }
+    }
+
+    // Log the first player's sound register writes
+    if let Some(path) = options.vgm.as_ref() {
+        players[0].mem.apu.start_vgm(path).map_err(|why| format!("couldn't create {}: {}", path.display(), why))?;
+    }
+
+    // Stop after so many frames or emulated seconds
+    let frame_limit = options.frames.unwrap_or(u64::MAX);
+    let cycle_limit = options.seconds.map_or(u64::MAX, |seconds| (seconds * M_CYCLES_PER_SECOND as f64) as u64);
+
+    let mut frames = 0;
+
+    // Sound playing on the audio device paces the emulation by its queue,
+    // the clock otherwise. Tab switches between throttled and unthrottled
+    // running.
+    let audio_pacer = AudioPacer::new(options.sample_rate, Duration::from_millis(50));
+    let mut frame_pacer = FramePacer::new(if options.speed > 0.0 { options.speed } else { 1.0 });
+    let mut throttled = options.speed > 0.0;
+    while frames < frame_limit && players[0].cycles < cycle_limit {
+        run_frame_linked(&mut players, adapter.as_mut());
+        frames += 1;
+
+        // Stop instead of showing a frozen screen
+        for gb in players.iter() {
+            gb.cpu.check()?;
+        }
+
+        let gb = &mut players[0];
+        if let Some(window) = window.as_mut() {
+            if !window.is_open() || window.is_key_down(Key::Escape) {
+                break;
             }
-        });
-    });
-    window.update_with_buffer(&buffer, WIDTH, HEIGHT).unwrap();
-    
-    // Press enter to exit
-    println!("\nPress enter to exit...");
-    stdin().read(&mut [0]).unwrap();
+            gb.render(&mut buffer);
+            window.update_with_buffer(&buffer, width, height).map_err(|why| format!("couldn't draw window: {}", why))?;
+            handle_keys(window, gb);
+
+            if window.is_key_pressed(Key::Tab, KeyRepeat::No) {
+                throttled = !throttled;
+                frame_pacer.reset();
+            }
+        }
+
+        if throttled && !audio_pacer.sync(&mut gb.mem.apu) {
+            frame_pacer.wait();
+        }
+    }
+
+    players[0].mem.apu.stop_vgm();
 
+    let gb = &players[0];
+    if !gb.serial.echo_stdout && !gb.serial.output().is_empty() {
+        println!("Serial output:\n{}", String::from_utf8_lossy(gb.serial.output()));
+    }
+
+    Ok(())
+}
+
+// Joypad and channel mute/solo controls
+fn handle_keys(window: &Window, gb: &mut GameBoy) {
+    for (key, button) in KEYS.iter() {
+        gb.set_button(*button, window.is_key_down(*key));
+    }
+
+    let shift = window.is_key_down(Key::LeftShift) || window.is_key_down(Key::RightShift);
+    for (channel, key) in CHANNEL_KEYS.iter().enumerate() {
+        if !window.is_key_pressed(*key, KeyRepeat::No) {
+            continue;
+        }
+        let apu = &mut gb.mem.apu;
+        if shift {
+            let solo = apu.solo();
+            apu.set_solo(if solo == Some(channel) { None } else { Some(channel) });
+        } else {
+            apu.set_muted(channel, !apu.is_muted(channel));
+        }
+        debug!("Channel {}: {:?}", channel + 1, apu.channel_info(channel));
+    }
 }
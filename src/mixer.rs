@@ -0,0 +1,181 @@
+use std::f64::consts::PI;
+
+// Output stage of the APU: the capacitor high-pass filter and resampling
+// from the 1 MiHz mixing rate down to the output rate.
+
+// Capacitor charge factor per 4 MiHz clock
+const DMG_CHARGE: f64 = 0.999958;
+const CGB_CHARGE: f64 = 0.998943;
+
+// Resampler kernel: zero crossings on each side, table entries per input sample
+// and the cutoff as a fraction of the output rate
+const ZERO_CROSSINGS: f64 = 8.0;
+const KERNEL_RESOLUTION: f64 = 16.0;
+const CUTOFF: f64 = 0.45;
+
+// Removes the DC offset of the DACs like the capacitors on the audio output
+pub struct HighPass {
+    charge: f32,
+    capacitor: [f32; 2]
+}
+
+impl HighPass {
+    // `rate` is the rate `apply` is called at
+    pub fn new(cgb: bool, rate: u32) -> HighPass {
+        let charge = if cgb { CGB_CHARGE } else { DMG_CHARGE };
+        HighPass {
+            charge: charge.powf(4194304.0 / rate as f64) as f32,
+            capacitor: [0.0; 2]
+        }
+    }
+
+    // The capacitor only charges while a DAC is on
+    pub fn apply(&mut self, frame: [f32; 2], dacs_enabled: bool) -> [f32; 2] {
+        if !dacs_enabled {
+            return [0.0; 2];
+        }
+
+        let mut out = [0.0; 2];
+        for (i, sample) in frame.iter().enumerate() {
+            out[i] = sample - self.capacitor[i];
+            self.capacitor[i] = sample - out[i] * self.charge;
+        }
+        out
+    }
+}
+
+// Band-limited resampler for stereo frames, a Blackman windowed sinc low-pass
+// evaluated at every output position
+pub struct Resampler {
+    // Input frames per output frame, nominal and adjusted
+    base_step: f64,
+    step: f64,
+    half_width: f64,
+    kernel: Vec<f32>,
+    input: Vec<[f32; 2]>,
+    // Position of the next output frame in `input`
+    pos: f64
+}
+
+impl Resampler {
+    pub fn new(in_rate: u32, out_rate: u32) -> Resampler {
+        let step = in_rate as f64 / out_rate as f64;
+        // Cutoff in cycles per input sample
+        let fc = CUTOFF / step;
+        let half_width = ZERO_CROSSINGS / (2.0 * fc);
+
+        let len = (2.0 * half_width * KERNEL_RESOLUTION) as usize + 1;
+        let kernel = (0..len).map(|i| {
+            let d = i as f64 / KERNEL_RESOLUTION - half_width;
+            let x = 2.0 * fc * d;
+            let sinc = if x == 0.0 { 1.0 } else { (PI * x).sin() / (PI * x) };
+            let t = (d + half_width) / (2.0 * half_width);
+            let window = 0.42 - 0.5 * (2.0 * PI * t).cos() + 0.08 * (4.0 * PI * t).cos();
+            (sinc * window) as f32
+        }).collect();
+
+        Resampler { base_step: step, step, half_width, kernel, input: Vec::new(), pos: half_width }
+    }
+
+    // Produce `ratio` times as many output frames, for small corrections only
+    // as the kernel stays tuned to the nominal rate
+    pub fn set_ratio(&mut self, ratio: f64) {
+        self.step = self.base_step / ratio;
+    }
+
+    pub fn ratio(&self) -> f64 {
+        self.base_step / self.step
+    }
+
+    // Add an input frame, finished output frames are appended interleaved to `out`
+    pub fn push(&mut self, frame: [f32; 2], out: &mut Vec<f32>) {
+        self.input.push(frame);
+
+        while self.pos + self.half_width < (self.input.len() - 1) as f64 {
+            out.extend_from_slice(&self.sample_at(self.pos));
+            self.pos += self.step;
+        }
+
+        // Drop input that no output frame reaches anymore
+        let consumed = (self.pos - self.half_width).floor() as usize;
+        if consumed > 4096 {
+            self.input.drain(..consumed);
+            self.pos -= consumed as f64;
+        }
+    }
+
+    fn sample_at(&self, pos: f64) -> [f32; 2] {
+        let first = (pos - self.half_width).ceil().max(0.0) as usize;
+        let last = ((pos + self.half_width).floor() as usize).min(self.input.len() - 1);
+
+        let mut acc = [0.0; 2];
+        let mut weights = 0.0;
+        for (k, frame) in self.input.iter().enumerate().take(last + 1).skip(first) {
+            let i = ((k as f64 - pos + self.half_width) * KERNEL_RESOLUTION).round() as usize;
+            let w = self.kernel[i.min(self.kernel.len() - 1)];
+            acc[0] += frame[0] * w;
+            acc[1] += frame[1] * w;
+            weights += w;
+        }
+
+        // Normalised so DC passes at unity gain
+        [acc[0] / weights, acc[1] / weights]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_high_pass() {
+        let mut filter = HighPass::new(false, 1 << 20);
+        let first = filter.apply([1.0, -1.0], true);
+        assert_eq!(first, [1.0, -1.0]);
+
+        // A constant offset decays
+        let mut last = first;
+        for _ in 0..1 << 20 {
+            last = filter.apply([1.0, -1.0], true);
+        }
+        assert!(last[0].abs() < 0.01 && last[1].abs() < 0.01);
+        assert_eq!(filter.apply([1.0, 1.0], false), [0.0, 0.0]);
+    }
+
+    // Amplitude of a sine after resampling 1 MiHz down to 48 kHz
+    fn resampled_amplitude(freq: f64) -> f32 {
+        let mut resampler = Resampler::new(1 << 20, 48000);
+        let mut out = Vec::new();
+        for i in 0..1 << 17 {
+            let x = (2.0 * PI * freq * i as f64 / (1 << 20) as f64).sin() as f32;
+            resampler.push([x, x], &mut out);
+        }
+        // Skip the start while the kernel fills up
+        out.iter().skip(out.len() / 2).fold(0.0, |max: f32, x| max.max(x.abs()))
+    }
+
+    #[test]
+    fn test_resampler() {
+        let mut resampler = Resampler::new(1 << 20, 48000);
+        let mut out = Vec::new();
+        for _ in 0..1 << 17 {
+            resampler.push([0.5, -0.5], &mut out);
+        }
+        // An eighth of a second, minus the kernel's latency
+        assert!(out.len() / 2 > 5900 && out.len() / 2 <= 6000);
+        assert!((out[out.len() - 2] - 0.5).abs() < 1e-4);
+        assert!((out[out.len() - 1] + 0.5).abs() < 1e-4);
+
+        // Slightly faster output
+        resampler.set_ratio(1.005);
+        let len = out.len();
+        for _ in 0..1 << 17 {
+            resampler.push([0.5, -0.5], &mut out);
+        }
+        assert_eq!((out.len() - len) / 2, 6030);
+
+        // Passes audible frequencies, removes what would alias
+        assert!(resampled_amplitude(1000.0) > 0.95);
+        assert!(resampled_amplitude(40000.0) < 0.01);
+    }
+}
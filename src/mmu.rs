@@ -1,6 +1,191 @@
+use crate::model::*;
+use crate::hdma::*;
+use crate::joypad::*;
+use crate::sgb::*;
+use crate::apu::*;
+
 use log::debug;
 
-pub type Memory = [u8; 0xFFFF + 1];
+use std::ops::{ Deref, DerefMut };
+
+const VRAM_BANK_SIZE: usize = 0x2000;
+const WRAM_BANK_SIZE: usize = 0x1000;
+const ROM_BANK_SIZE: usize = 0x4000;
+
+// Flat 64 KiB memory map holding whatever is currently mapped in.
+// On CGB the VRAM bank (VBK) and the WRAM bank at 0xD000 (SVBK) are swapped
+// in and out of the map when switched, the other banks are kept aside.
+pub struct Memory {
+    map: Box<[u8; 0xFFFF + 1]>,
+    vram: Vec<[u8; VRAM_BANK_SIZE]>,
+    wram: Vec<[u8; WRAM_BANK_SIZE]>,
+    vram_bank: usize,
+    wram_bank: usize,
+    // Banked ROM for GBS files, switched like MBC1 through 0x2000-0x3FFF.
+    // Empty for cartridges, whose ROM is copied into the map.
+    rom: Vec<u8>,
+    rom_bank: usize,
+    // Cartridge bytes under the boot rom while it's mapped in
+    boot_rom_hidden: Option<Vec<u8>>,
+    // CGB palette RAM, written through BCPS/BCPD and OCPS/OCPD
+    pub bg_palette: [u8; 64],
+    pub obj_palette: [u8; 64],
+    // Internal counter behind DIV
+    pub div: u16,
+    // CGB double speed mode, switched through KEY1 and STOP
+    pub double_speed: bool,
+    pub hdma: Hdma,
+    // M-cycles the CPU is halted for by DMA
+    pub dma_stall: u16,
+    pub joypad: Joypad,
+    pub apu: Apu,
+    // Present when running an SGB enhanced cartridge on an SGB
+    pub sgb: Option<Box<Sgb>>,
+    pub model: Model,
+    // CGB hardware running a CGB cartridge, as opposed to DMG compatibility mode
+    pub cgb_mode: bool
+}
+
+impl Memory {
+    pub fn new() -> Memory {
+        Memory::with_model(Model::Dmg, false)
+    }
+
+    pub fn with_model(model: Model, cgb_mode: bool) -> Memory {
+        Memory {
+            map: Box::new([0; 0xFFFF + 1]),
+            vram: vec![[0; VRAM_BANK_SIZE]; 2],
+            wram: vec![[0; WRAM_BANK_SIZE]; 8],
+            vram_bank: 0,
+            wram_bank: 1,
+            rom: Vec::new(),
+            rom_bank: 1,
+            boot_rom_hidden: None,
+            bg_palette: [0xFF; 64],
+            obj_palette: [0xFF; 64],
+            div: 0,
+            double_speed: false,
+            hdma: Hdma::new(),
+            dma_stall: 0,
+            joypad: Joypad::new(),
+            apu: Apu::with_model(model),
+            sgb: None,
+            model,
+            cgb_mode
+        }
+    }
+
+    // Contents of a VRAM bank, mapped in or not
+    pub fn vram_bank(&self, bank: usize) -> &[u8] {
+        match bank == self.vram_bank {
+            true => &self.map[0x8000..0xA000],
+            false => &self.vram[bank]
+        }
+    }
+
+    fn switch_vram_bank(&mut self, bank: usize) {
+        if bank != self.vram_bank {
+            self.vram[self.vram_bank].copy_from_slice(&self.map[0x8000..0xA000]);
+            self.map[0x8000..0xA000].copy_from_slice(&self.vram[bank]);
+            self.vram_bank = bank;
+        }
+    }
+
+    // PPU is reading palette RAM and VRAM
+    fn drawing(&self) -> bool {
+        self.map[0xFF40] & 0x80 != 0 && self.map[0xFF41] & 0x03 == 3
+    }
+
+    // BCPD/OCPD write, the index register at `spec_adr` auto-increments
+    // when bit 7 is set, even when the write itself is blocked
+    fn write_palette(&mut self, spec_adr: usize, val: u8) {
+        let spec = self.map[spec_adr];
+        let index = (spec & 0x3F) as usize;
+        if !self.drawing() {
+            match spec_adr {
+                0xFF68 => self.bg_palette[index] = val,
+                _ => self.obj_palette[index] = val
+            }
+        }
+        if spec & 0x80 != 0 {
+            self.map[spec_adr] = (spec & 0x80) | ((spec + 1) & 0x3F);
+        }
+        self.sync_palette_data(spec_adr);
+    }
+
+    // Keep BCPD/OCPD reading the entry BCPS/OCPS points at
+    fn sync_palette_data(&mut self, spec_adr: usize) {
+        let index = (self.map[spec_adr] & 0x3F) as usize;
+        self.map[spec_adr + 1] = match spec_adr {
+            0xFF68 => self.bg_palette[index],
+            _ => self.obj_palette[index]
+        };
+    }
+
+    // Map in bank 0 and 1 of `rom`, writes to the ROM area switch banks
+    // from then on
+    pub fn load_banked_rom(&mut self, rom: &[u8]) {
+        let banks = rom.len().div_ceil(ROM_BANK_SIZE).max(2);
+        self.rom = rom.to_vec();
+        self.rom.resize(banks * ROM_BANK_SIZE, 0);
+        self.map[..ROM_BANK_SIZE * 2].copy_from_slice(&self.rom[..ROM_BANK_SIZE * 2]);
+        self.rom_bank = 1;
+    }
+
+    // Bank 0 selects bank 1, numbers past the end wrap around
+    fn switch_rom_bank(&mut self, bank: usize) {
+        let bank = bank.max(1) % (self.rom.len() / ROM_BANK_SIZE);
+        let start = bank * ROM_BANK_SIZE;
+        self.map[0x4000..0x8000].copy_from_slice(&self.rom[start..start + ROM_BANK_SIZE]);
+        self.rom_bank = bank;
+    }
+
+    // Map a boot rom over the cartridge until 0xFF50 is written. CGB boot
+    // roms leave a gap for the cartridge header at 0x0100-0x01FF.
+    pub fn map_boot_rom(&mut self, boot_rom: &[u8]) {
+        let len = boot_rom.len().min(0x8000);
+        self.boot_rom_hidden = Some(self.map[..len].to_vec());
+        for (adr, val) in boot_rom[..len].iter().enumerate() {
+            if !(0x0100..0x0200).contains(&adr) {
+                self.map[adr] = *val;
+            }
+        }
+    }
+
+    fn unmap_boot_rom(&mut self) {
+        if let Some(hidden) = self.boot_rom_hidden.take() {
+            self.map[..hidden.len()].copy_from_slice(&hidden);
+        }
+    }
+
+    fn switch_wram_bank(&mut self, bank: usize) {
+        if bank != self.wram_bank {
+            self.wram[self.wram_bank].copy_from_slice(&self.map[0xD000..0xE000]);
+            self.map[0xD000..0xE000].copy_from_slice(&self.wram[bank]);
+            self.wram_bank = bank;
+        }
+    }
+}
+
+impl Default for Memory {
+    fn default() -> Memory {
+        Memory::new()
+    }
+}
+
+impl Deref for Memory {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.map[..]
+    }
+}
+
+impl DerefMut for Memory {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.map[..]
+    }
+}
 
 pub fn init_memory(mem: &mut Memory) {
     mem[0x0000..0xFFFF + 1].iter_mut().for_each(|x| *x = 0x0000);
@@ -18,43 +203,160 @@ pub fn init_memory(mem: &mut Memory) {
         (0xFF69, 0xFF), (0xFF6A, 0xFF), (0xFF6B, 0xFF), (0xFF70, 0xFF)
     ];
 
+    // Stored directly, writing some of these registers kicks off hardware
     for (adr, val) in io.iter() {
-        write_byte(*adr, *val, mem);
+        mem[*adr as usize] = *val;
+    }
+    mem.div = (mem[0xFF04] as u16) << 8;
+    let sound = mem[0xFF10..0xFF40].to_vec();
+    mem.apu.load_registers(&sound);
+}
+
+// IO registers that differ from the DMG defaults set by init_memory
+pub fn init_model_io(mem: &mut Memory) {
+    let model = mem.model;
+    let cgb_mode = mem.cgb_mode;
+    let mut io: Vec<(u16, u8)> = Vec::new();
+
+    if model == Model::Dmg0 {
+        io.push((0xFF41, 0x81));
+    }
+
+    if model.is_cgb() {
+        io.push((0xFF02, 0x7F));
+
+        // CGB registers are locked in DMG compatibility mode
+        if cgb_mode {
+            io.extend_from_slice(&[
+                (0xFF4D, 0x7E), (0xFF4F, 0xFE), (0xFF56, 0x3E), (0xFF6C, 0xFE),
+                (0xFF70, 0xF8)
+            ]);
+        }
+    }
+
+    for (adr, val) in io.iter() {
+        mem[*adr as usize] = *val;
+    }
+}
+
+// Perform the speed switch prepared through KEY1, called by STOP
+pub fn switch_speed(mem: &mut Memory) -> bool {
+    if !mem.cgb_mode || mem[0xFF4D] & 0x01 == 0 {
+        return false;
     }
+
+    mem.double_speed = !mem.double_speed;
+    mem[0xFF4D] = 0x7E | ((mem.double_speed as u8) << 7);
+    mem.div = 0;
+    mem[0xFF04] = 0;
+    true
 }
 
 // Read byte from memory
-pub fn read_byte(adr: u16, mem: &[u8]) -> u8 {
+pub fn read_byte(adr: u16, mem: &Memory) -> u8 {
     print_debug("Read byte", adr);
 
-    // TODO: This is a placeholder for proper button reading
     if adr == 0xFF00 {
-        return 0xEF;
+        return read_joypad(mem);
+    }
+
+    if (0xFF10..=0xFF3F).contains(&adr) {
+        return mem.apu.read(adr);
+    }
+
+    // Palette RAM can't be read while the PPU is drawing
+    if (adr == 0xFF69 || adr == 0xFF6B) && mem.cgb_mode && mem.drawing() {
+        return 0xFF;
     }
 
     mem[adr as usize]
 }
 
 // Write word to memory
-pub fn write_byte(adr: u16, val: u8, mem: &mut [u8]) {
+pub fn write_byte(adr: u16, val: u8, mem: &mut Memory) {
     print_debug("Write byte", adr);
 
-    mem[adr as usize] = val
+    match adr {
+        // P1 - joypad
+        0xFF00 => write_joypad(val, mem),
+
+        // ROM bank select, other writes to banked ROM are ignored
+        0x2000 ..= 0x3FFF if !mem.rom.is_empty() => mem.switch_rom_bank(val as usize),
+        0x0000 ..= 0x7FFF if !mem.rom.is_empty() => {},
+
+        // BANK - any write unmaps the boot rom for good
+        0xFF50 => {
+            mem.unmap_boot_rom();
+            mem[adr as usize] = 0xFF;
+        },
+
+        // NR10-NR52 and wave RAM
+        0xFF10 ..= 0xFF3F => mem.apu.write(adr, val),
+
+        // DIV - any write resets the divider
+        0xFF04 => {
+            mem.div = 0;
+            mem[adr as usize] = 0;
+        },
+
+        // KEY1 - only the prepare bit is writable
+        0xFF4D if mem.cgb_mode => {
+            mem[adr as usize] = (mem[adr as usize] & 0x80) | 0x7E | (val & 0x01);
+        },
+
+        // HDMA1-HDMA4 - VRAM DMA source and destination, write only
+        0xFF51 if mem.cgb_mode => mem.hdma.src = (mem.hdma.src & 0x00F0) | (val as u16) << 8,
+        0xFF52 if mem.cgb_mode => mem.hdma.src = (mem.hdma.src & 0xFF00) | (val & 0xF0) as u16,
+        0xFF53 if mem.cgb_mode => mem.hdma.dst = (mem.hdma.dst & 0x00F0) | ((val & 0x1F) as u16) << 8,
+        0xFF54 if mem.cgb_mode => mem.hdma.dst = (mem.hdma.dst & 0x1F00) | (val & 0xF0) as u16,
+
+        // HDMA5 - VRAM DMA length, mode and start
+        0xFF55 if mem.cgb_mode => start_hdma(val, mem),
+
+        // VBK - VRAM bank
+        0xFF4F if mem.cgb_mode => {
+            mem.switch_vram_bank((val & 0x01) as usize);
+            mem[adr as usize] = 0xFE | val;
+        },
+
+        // SVBK - WRAM bank, bank 0 selects bank 1
+        0xFF70 if mem.cgb_mode => {
+            mem.switch_wram_bank(((val & 0x07) as usize).max(1));
+            mem[adr as usize] = 0xF8 | val;
+        },
+
+        // RP - infrared port, bit 1 is driven by the receiver
+        0xFF56 if mem.cgb_mode => mem[adr as usize] = 0x3E | (val & 0xC1),
+
+        // OPRI - object priority mode
+        0xFF6C if mem.cgb_mode => mem[adr as usize] = 0xFE | val,
+
+        // BCPS/OCPS - palette index
+        0xFF68 | 0xFF6A if mem.cgb_mode => {
+            mem[adr as usize] = val & 0xBF;
+            mem.sync_palette_data(adr as usize);
+        },
+
+        // BCPD/OCPD - palette data
+        0xFF69 | 0xFF6B if mem.cgb_mode => mem.write_palette(adr as usize - 1, val),
+
+        _ => mem[adr as usize] = val
+    }
 }
 
 // Read word from memory (lil' endian?)
-pub fn read_word(adr: u16, mem: &[u8]) -> u16 {
+pub fn read_word(adr: u16, mem: &Memory) -> u16 {
     print_debug("Read word", adr);
     
-    mem[adr as usize] as u16 | ((mem[(adr + 1) as usize] as u16) << 8)
+    mem[adr as usize] as u16 | ((mem[adr.wrapping_add(1) as usize] as u16) << 8)
 }
 
 // Write word to memory
-pub fn write_word(adr: u16, val: u16, mem: &mut [u8]) {
+pub fn write_word(adr: u16, val: u16, mem: &mut Memory) {
     print_debug("Write word", adr);
 
     mem[adr as usize] = (val & 0x00FF) as u8;
-    mem[(adr + 1) as usize] = (val >> 8) as u8;
+    mem[adr.wrapping_add(1) as usize] = (val >> 8) as u8;
 }
 
 pub fn read_bit(adr: u16, bit: u8, mem: &Memory) -> u8 {
@@ -94,7 +396,7 @@ mod tests {
 
     #[test]
     fn test_write_byte() {
-        let mut mem: Memory = [0; 0xFFFF + 1];
+        let mut mem = Memory::new();
         let adr = 0xFFFE;
         let val = 0xFF;
         write_byte(adr, val, &mut mem);
@@ -103,7 +405,7 @@ mod tests {
 
     #[test]
     fn init_memory_test() {
-        let mut mem: Memory = [0; 0xFFFF + 1];
+        let mut mem = Memory::new();
         init_memory(&mut mem);
         assert_eq!(0x00, read_byte(0x7FFF, &mut mem));
         assert_eq!(0xCF, read_byte(0xFF00, &mut mem));
@@ -111,9 +413,148 @@ mod tests {
         assert_eq!(0x00, read_byte(0xFFFF, &mut mem));
     }
 
+    #[test]
+    fn init_model_io_test() {
+        let mut mem = Memory::with_model(Model::Cgb, false);
+        init_memory(&mut mem);
+        init_model_io(&mut mem);
+        assert_eq!(0x7F, read_byte(0xFF02, &mem));
+        assert_eq!(0xFF, read_byte(0xFF70, &mem));
+
+        let mut mem = Memory::with_model(Model::Cgb, true);
+        init_memory(&mut mem);
+        init_model_io(&mut mem);
+        assert_eq!(0xFE, read_byte(0xFF4F, &mem));
+        assert_eq!(0xF8, read_byte(0xFF70, &mem));
+    }
+
+    #[test]
+    fn test_vram_banking() {
+        let mut mem = Memory::with_model(Model::Cgb, true);
+        write_byte(0x8000, 0x11, &mut mem);
+        write_byte(0xFF4F, 0x01, &mut mem);
+        assert_eq!(0xFF, read_byte(0xFF4F, &mem));
+        assert_eq!(0x00, read_byte(0x8000, &mem));
+        write_byte(0x8000, 0x22, &mut mem);
+        assert_eq!(0x11, mem.vram_bank(0)[0]);
+        assert_eq!(0x22, mem.vram_bank(1)[0]);
+
+        write_byte(0xFF4F, 0x00, &mut mem);
+        assert_eq!(0xFE, read_byte(0xFF4F, &mem));
+        assert_eq!(0x11, read_byte(0x8000, &mem));
+    }
+
+    #[test]
+    fn test_wram_banking() {
+        let mut mem = Memory::with_model(Model::Cgb, true);
+        write_byte(0xD000, 0x11, &mut mem);
+        write_byte(0xFF70, 0x02, &mut mem);
+        assert_eq!(0x00, read_byte(0xD000, &mem));
+        write_byte(0xD000, 0x22, &mut mem);
+
+        // Bank 0 maps to bank 1, 0xC000-0xCFFF is never switched
+        write_byte(0xC000, 0x33, &mut mem);
+        write_byte(0xFF70, 0x00, &mut mem);
+        assert_eq!(0xF8, read_byte(0xFF70, &mem));
+        assert_eq!(0x11, read_byte(0xD000, &mem));
+        assert_eq!(0x33, read_byte(0xC000, &mem));
+
+        write_byte(0xFF70, 0x02, &mut mem);
+        assert_eq!(0x22, read_byte(0xD000, &mem));
+    }
+
+    #[test]
+    fn test_boot_rom() {
+        let mut mem = Memory::new();
+        mem[0x0000] = 0x11;
+        mem[0x0100] = 0x22;
+        mem[0x0200] = 0x33;
+        mem.map_boot_rom(&[0xAA; 0x900]);
+        assert_eq!(0xAA, read_byte(0x0000, &mem));
+        assert_eq!(0x22, read_byte(0x0100, &mem));
+        assert_eq!(0xAA, read_byte(0x0200, &mem));
+
+        write_byte(0xFF50, 0x01, &mut mem);
+        assert_eq!(0x11, read_byte(0x0000, &mem));
+        assert_eq!(0x33, read_byte(0x0200, &mem));
+    }
+
+    #[test]
+    fn test_rom_banking() {
+        let mut rom = vec![0; ROM_BANK_SIZE * 3];
+        rom[0x0000] = 0x10;
+        rom[ROM_BANK_SIZE] = 0x11;
+        rom[ROM_BANK_SIZE * 2] = 0x12;
+        let mut mem = Memory::new();
+        mem.load_banked_rom(&rom);
+        assert_eq!(0x11, read_byte(0x4000, &mem));
+
+        write_byte(0x2000, 0x02, &mut mem);
+        assert_eq!(0x12, read_byte(0x4000, &mem));
+        write_byte(0x0000, 0xFF, &mut mem);
+        assert_eq!(0x10, read_byte(0x0000, &mem));
+        write_byte(0x3FFF, 0x00, &mut mem);
+        assert_eq!(0x11, read_byte(0x4000, &mem));
+    }
+
+    #[test]
+    fn test_palette_auto_increment() {
+        let mut mem = Memory::with_model(Model::Cgb, true);
+        write_byte(0xFF68, 0x80, &mut mem);
+        write_byte(0xFF69, 0x1F, &mut mem);
+        write_byte(0xFF69, 0x7C, &mut mem);
+        assert_eq!(0x82, read_byte(0xFF68, &mem));
+        assert_eq!([0x1F, 0x7C], mem.bg_palette[0..2]);
+
+        write_byte(0xFF68, 0x01, &mut mem);
+        assert_eq!(0x7C, read_byte(0xFF69, &mem));
+        write_byte(0xFF69, 0x00, &mut mem);
+        assert_eq!(0x01, read_byte(0xFF68, &mem));
+        assert_eq!(0x00, read_byte(0xFF69, &mem));
+    }
+
+    #[test]
+    fn test_palette_blocked_while_drawing() {
+        let mut mem = Memory::with_model(Model::Cgb, true);
+        write_byte(0xFF40, 0x80, &mut mem);
+        write_byte(0xFF41, 0x03, &mut mem);
+        write_byte(0xFF6A, 0x80, &mut mem);
+        write_byte(0xFF6B, 0x1F, &mut mem);
+        assert_eq!(0xFF, mem.obj_palette[0]);
+        assert_eq!(0x81, read_byte(0xFF6A, &mem));
+        assert_eq!(0xFF, read_byte(0xFF6B, &mem));
+    }
+
+    #[test]
+    fn test_switch_speed() {
+        let mut mem = Memory::with_model(Model::Cgb, true);
+        init_memory(&mut mem);
+        init_model_io(&mut mem);
+        assert!(!switch_speed(&mut mem));
+
+        write_byte(0xFF4D, 0x01, &mut mem);
+        assert_eq!(0x7F, read_byte(0xFF4D, &mem));
+        assert!(switch_speed(&mut mem));
+        assert!(mem.double_speed);
+        assert_eq!(0xFE, read_byte(0xFF4D, &mem));
+        assert_eq!(0x00, read_byte(0xFF04, &mem));
+
+        let mut mem = Memory::new();
+        write_byte(0xFF4D, 0x01, &mut mem);
+        assert!(!switch_speed(&mut mem));
+    }
+
+    #[test]
+    fn test_no_banking_on_dmg() {
+        let mut mem = Memory::new();
+        write_byte(0x8000, 0x11, &mut mem);
+        write_byte(0xFF4F, 0x01, &mut mem);
+        assert_eq!(0x11, read_byte(0x8000, &mem));
+    }
+
     #[test]
     fn test_read_bit(){
-        let mut mem: Memory = [0; 0xFFFF + 1];
+        let mut mem = Memory::new();
         write_byte(0x0100, 0xCF, &mut mem);
         assert!(0xCF == read_byte(0x0100, &mut mem),
             "Failed to initialize memory");
@@ -127,7 +568,7 @@ mod tests {
 
     #[test]
     fn test_bits_to_number(){
-        let mut mem: Memory = [0; 0xFFFF + 1];
+        let mut mem = Memory::new();
         write_byte(0x0100, 0xCF, &mut mem);
         assert!(0xCF == read_byte(0x0100, &mut mem),
             "Failed to initialize memory");
@@ -0,0 +1,70 @@
+use crate::cartridge::*;
+
+use std::fmt;
+use std::str::FromStr;
+
+// Hardware model being emulated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Model {
+    Dmg0,
+    Dmg,
+    Mgb,
+    Sgb,
+    Sgb2,
+    Cgb,
+    Agb
+}
+
+impl Model {
+    // Pick the model a cartridge was made for
+    pub fn detect(header: &Header) -> Model {
+        if header.supports_cgb() {
+            Model::Cgb
+        } else if header.supports_sgb() {
+            Model::Sgb
+        } else {
+            Model::Dmg
+        }
+    }
+
+    // CGB hardware, including the GBA running Game Boy software
+    pub fn is_cgb(&self) -> bool {
+        matches!(self, Model::Cgb | Model::Agb)
+    }
+
+    pub fn is_sgb(&self) -> bool {
+        matches!(self, Model::Sgb | Model::Sgb2)
+    }
+}
+
+impl FromStr for Model {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Model, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "dmg0" => Ok(Model::Dmg0),
+            "dmg" => Ok(Model::Dmg),
+            "mgb" => Ok(Model::Mgb),
+            "sgb" => Ok(Model::Sgb),
+            "sgb2" => Ok(Model::Sgb2),
+            "cgb" => Ok(Model::Cgb),
+            "agb" => Ok(Model::Agb),
+            _ => Err(format!("unknown model '{}', expected one of dmg0, dmg, mgb, sgb, sgb2, cgb, agb", s))
+        }
+    }
+}
+
+impl fmt::Display for Model {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Model::Dmg0 => "DMG0",
+            Model::Dmg => "DMG",
+            Model::Mgb => "MGB",
+            Model::Sgb => "SGB",
+            Model::Sgb2 => "SGB2",
+            Model::Cgb => "CGB",
+            Model::Agb => "AGB"
+        };
+        write!(f, "{}", name)
+    }
+}
@@ -0,0 +1,192 @@
+// Pacing the emulation to real time.
+//
+// Without audio to follow, FramePacer holds each frame to its 59.73 Hz slot.
+// Deadlines are absolute, so a frame that ends late is made up for by the
+// next ones instead of adding up, unless it's so far behind that catching up
+// would run visibly fast. It sleeps until shortly before the deadline and
+// spins the rest, sleeps overshoot by too much to hit it precisely.
+//
+// With a realtime audio sink like DeviceSink the sound device sets the
+// speed: emulation runs until the device's queue holds enough audio, then
+// waits for it to drain. The device clock never exactly matches the emulated
+// one, so the queue level also nudges the resampling ratio (dynamic rate
+// control). Below the target slightly more samples are produced, above it
+// slightly fewer, at most MAX_RATE_DELTA off which is too little to hear as a
+// pitch change.
+
+use crate::apu::*;
+use crate::gameboy::M_CYCLES_PER_FRAME;
+
+use std::thread;
+use std::time::{ Duration, Instant };
+
+pub const MAX_RATE_DELTA: f64 = 0.005;
+
+// How long to sleep between checks on the queue
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+// Emulated frames per second, 59.73
+pub const FRAME_RATE: f64 = M_CYCLES_PER_SECOND as f64 / M_CYCLES_PER_FRAME as f64;
+
+// Spun instead of slept before a deadline
+const SPIN_MARGIN: Duration = Duration::from_millis(2);
+// Lag given up on instead of caught up with
+const MAX_LAG: Duration = Duration::from_millis(100);
+
+pub struct FramePacer {
+    frame: Duration,
+    deadline: Instant
+}
+
+impl FramePacer {
+    // `speed` of 1.0 is real time
+    pub fn new(speed: f64) -> FramePacer {
+        FramePacer { frame: Duration::from_secs_f64(1.0 / (FRAME_RATE * speed)), deadline: Instant::now() }
+    }
+
+    // Start counting from now, after running unthrottled
+    pub fn reset(&mut self) {
+        self.deadline = Instant::now();
+    }
+
+    // Called after each frame, waits for the end of its slot
+    pub fn wait(&mut self) {
+        let Some(deadline) = self.advance(Instant::now()) else {
+            return;
+        };
+        if let Some(sleep) = deadline.checked_duration_since(Instant::now() + SPIN_MARGIN) {
+            thread::sleep(sleep);
+        }
+        while Instant::now() < deadline {
+            std::hint::spin_loop();
+        }
+    }
+
+    // Moves on to the next slot, returns when it ends or None when `now` is
+    // too far behind and the slots start over from it
+    fn advance(&mut self, now: Instant) -> Option<Instant> {
+        self.deadline += self.frame;
+        if now > self.deadline + MAX_LAG {
+            self.deadline = now;
+            return None;
+        }
+        Some(self.deadline)
+    }
+}
+
+pub struct AudioPacer {
+    // Queued stereo frames to aim for
+    target: usize
+}
+
+impl AudioPacer {
+    // Keep about `latency` of audio queued
+    pub fn new(sample_rate: u32, latency: Duration) -> AudioPacer {
+        AudioPacer { target: (sample_rate as f64 * latency.as_secs_f64()) as usize }
+    }
+
+    // Resampling ratio for a queue level, 1.0 at the target
+    pub fn rate_adjust(&self, queued: usize) -> f64 {
+        let fill = (queued as f64 / (2 * self.target.max(1)) as f64).min(1.0);
+        1.0 + MAX_RATE_DELTA * (1.0 - 2.0 * fill)
+    }
+
+    // Called once per frame. Returns false when the APU has no realtime sink
+    // and the caller has to pace by time instead.
+    pub fn sync(&self, apu: &mut Apu) -> bool {
+        let Some(mut queued) = apu.queued_audio() else {
+            return false;
+        };
+
+        // Measured before waiting, after it the queue is never above target
+        apu.set_rate_adjust(self.rate_adjust(queued));
+        while queued > self.target {
+            thread::sleep(POLL_INTERVAL);
+            queued = apu.queued_audio().unwrap_or(0);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_sink::*;
+    use crate::model::*;
+
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    // Device playing a fixed number of frames between checks on its queue
+    struct FakeDevice {
+        queued: Rc<Cell<usize>>,
+        drain: usize,
+        polls: Rc<Cell<usize>>
+    }
+
+    impl AudioSink for FakeDevice {
+        fn push(&mut self, samples: &[f32]) {
+            self.queued.set(self.queued.get() + samples.len() / 2);
+        }
+
+        fn queued(&self) -> Option<usize> {
+            self.polls.set(self.polls.get() + 1);
+            let queued = self.queued.get();
+            self.queued.set(queued.saturating_sub(self.drain));
+            Some(queued)
+        }
+    }
+
+    #[test]
+    fn test_frame_pacer() {
+        let mut pacer = FramePacer::new(2.0);
+        let start = pacer.deadline;
+        for i in 1..=10 {
+            assert_eq!(pacer.advance(start), Some(start + pacer.frame * i));
+        }
+
+        // A slow frame is made up for by the next
+        let late = start + pacer.frame * 11 + Duration::from_millis(12);
+        assert_eq!(pacer.advance(late), Some(start + pacer.frame * 11));
+        assert_eq!(pacer.advance(late), Some(start + pacer.frame * 12));
+
+        // Too far behind to catch up
+        let late = start + pacer.frame * 13 + MAX_LAG * 2;
+        assert_eq!(pacer.advance(late), None);
+        assert_eq!(pacer.advance(late), Some(late + pacer.frame));
+    }
+
+    #[test]
+    fn test_rate_adjust() {
+        let pacer = AudioPacer::new(48000, Duration::from_millis(50));
+        assert_eq!(pacer.target, 2400);
+        assert_eq!(pacer.rate_adjust(2400), 1.0);
+        assert_eq!(pacer.rate_adjust(0), 1.0 + MAX_RATE_DELTA);
+        assert_eq!(pacer.rate_adjust(100000), 1.0 - MAX_RATE_DELTA);
+        assert!(pacer.rate_adjust(1000) > pacer.rate_adjust(2000));
+    }
+
+    #[test]
+    fn test_sync() {
+        let pacer = AudioPacer::new(48000, Duration::from_millis(20));
+        let mut apu = Apu::with_model(Model::Dmg);
+        assert!(!pacer.sync(&mut apu));
+
+        // Under the target, no waiting
+        let queued = Rc::new(Cell::new(480));
+        let polls = Rc::new(Cell::new(0));
+        apu.set_sink(Box::new(FakeDevice { queued: queued.clone(), drain: 480, polls: polls.clone() }));
+        assert!(pacer.sync(&mut apu));
+        assert_eq!(polls.get(), 1);
+        assert!(apu.rate_adjust() > 1.0);
+
+        // Waits for 100 ms of audio to drain to 20 ms
+        queued.set(4800);
+        polls.set(0);
+        assert!(pacer.sync(&mut apu));
+        assert_eq!(polls.get(), 9);
+        assert_eq!(queued.get(), 480);
+        // Above the target slows down
+        assert!(apu.rate_adjust() < 1.0);
+    }
+}
@@ -1,3 +1,6 @@
+use crate::cartridge::*;
+use crate::model::*;
+
 use log::debug;
 
 pub struct Registers {
@@ -41,6 +44,54 @@ impl Registers {
         }
     }
 
+    // Undefined at power on, zero here. The boot rom sets up the stack.
+    pub fn power_on() -> Registers {
+        Registers { a: 0, f: 0, b: 0, c: 0, d: 0, e: 0, h: 0, l: 0, sp: 0, pc: 0, ime: false }
+    }
+
+    // Values left behind by each model's bootstrap ROM
+    // https://gbdev.io/pandocs/Power_Up_Sequence.html#cpu-registers
+    pub fn after_boot(model: Model, header: &Header) -> Registers {
+        let mut reg = Registers::new();
+        // H and C are set unless the header checksum is 0x00
+        let dmg_flags = match header.header_checksum {
+            0x00 => 0x80,
+            _ => 0xB0
+        };
+
+        let (a, f, b, c, d, e, h, l) = match model {
+            Model::Dmg0 => (0x01, 0x00, 0xFF, 0x13, 0x00, 0xC1, 0x84, 0x03),
+            Model::Dmg => (0x01, dmg_flags, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D),
+            Model::Mgb => (0xFF, dmg_flags, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D),
+            Model::Sgb => (0x01, 0x00, 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60),
+            Model::Sgb2 => (0xFF, 0x00, 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60),
+            Model::Cgb | Model::Agb if header.supports_cgb() => {
+                (0x11, 0x80, 0x00, 0x00, 0xFF, 0x56, 0x00, 0x0D)
+            },
+            // DMG compatibility mode, the boot rom checksums licensed titles
+            Model::Cgb | Model::Agb => match header.is_nintendo() {
+                true => (0x11, 0x80, header.title_checksum(), 0x00, 0x00, 0x08, 0x99, 0x1A),
+                false => (0x11, 0x80, 0x00, 0x00, 0x00, 0x08, 0x00, 0x7C)
+            }
+        };
+        reg.a = a;
+        reg.f = f;
+        reg.b = b;
+        reg.c = c;
+        reg.d = d;
+        reg.e = e;
+        reg.h = h;
+        reg.l = l;
+
+        // The GBA boot rom ends with an extra INC B
+        if model == Model::Agb {
+            reg.b = reg.b.wrapping_add(1);
+            reg.set_flags(reg.b == 0, false, reg.b & 0x0F == 0, reg.get_flag(Flag::C));
+        }
+
+        reg
+    }
+
     pub fn af(&self) -> u16 {
         (self.a as u16) << 8 | (self.f as u16)
     }
@@ -117,6 +168,12 @@ impl Registers {
     }
 }
 
+impl Default for Registers {
+    fn default() -> Registers {
+        Registers::new()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -144,6 +201,26 @@ mod tests {
         assert_eq!(reg.hl(), 0x1111);
     }
 
+    #[test]
+    fn test_after_boot()
+    {
+        let mut rom = vec![0; 0x8000];
+        rom[0x014D] = 0x0A;
+        let reg = Registers::after_boot(Model::Dmg, &Header::parse(&rom));
+        assert_eq!(reg.af(), 0x01B0);
+        assert_eq!(reg.bc(), 0x0013);
+        assert_eq!(reg.hl(), 0x014D);
+
+        rom[0x0143] = 0x80;
+        let reg = Registers::after_boot(Model::Cgb, &Header::parse(&rom));
+        assert_eq!(reg.af(), 0x1180);
+        assert_eq!(reg.de(), 0xFF56);
+
+        let reg = Registers::after_boot(Model::Agb, &Header::parse(&rom));
+        assert_eq!(reg.af(), 0x1100);
+        assert_eq!(reg.b & 0x01, 0x01);
+    }
+
     #[test]
     fn test_flags()
     {
@@ -0,0 +1,208 @@
+use crate::mmu::*;
+
+use std::io::{ stdout, Write };
+
+// Internal clock shifts one bit at 8192 Hz, i.e. every 128 m-cycles
+const BIT_M_CYCLES: u16 = 128;
+
+// Messages exchanged between two linked serial ports. The side driving the
+// clock sends its byte, the other side answers with the byte it shifted out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkMessage {
+    Transfer(u8),
+    Reply(u8)
+}
+
+// Transport carrying link messages to the partner Game Boy
+pub trait SerialLink {
+    fn send(&mut self, msg: LinkMessage);
+    fn recv(&mut self) -> Option<LinkMessage>;
+}
+
+pub struct Serial {
+    clocks: u16,
+    bits_left: u8,
+    transferring: bool,
+    incoming: u8,
+    link: Option<Box<dyn SerialLink>>,
+    // Internal clock transfer is stalled until the partner replies
+    awaiting_reply: bool,
+    // Every byte sent over SB, test ROMs report their results this way
+    output: Vec<u8>,
+    pub echo_stdout: bool
+}
+
+impl Serial {
+    pub fn new() -> Serial {
+        Serial {
+            clocks: 0,
+            bits_left: 0,
+            transferring: false,
+            incoming: 0xFF,
+            link: None,
+            awaiting_reply: false,
+            output: Vec::new(),
+            echo_stdout: false
+        }
+    }
+
+    pub fn connect(&mut self, link: Box<dyn SerialLink>) {
+        self.link = Some(link);
+    }
+
+    // Bytes sent so far
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    fn capture(&mut self, byte: u8) {
+        self.output.push(byte);
+        if self.echo_stdout {
+            print!("{}", byte as char);
+            stdout().flush().unwrap();
+        }
+    }
+
+    pub fn tick(&mut self, mem: &mut Memory) {
+        self.poll_link(mem);
+
+        let sc = mem[0xFF02];
+
+        // Only internal clock transfers are driven from here. External clock
+        // transfers complete when the partner sends a byte, so with no link
+        // partner attached they never do.
+        if sc & 0x81 != 0x81 {
+            self.transferring = false;
+            self.awaiting_reply = false;
+            return;
+        }
+
+        if !self.transferring {
+            self.transferring = true;
+            self.clocks = 0;
+            self.bits_left = 8;
+            // Nothing is attached, the data line is pulled high
+            self.incoming = 0xFF;
+            self.capture(mem[0xFF01]);
+
+            if let Some(link) = self.link.as_mut() {
+                link.send(LinkMessage::Transfer(mem[0xFF01]));
+                self.awaiting_reply = true;
+            }
+        }
+
+        if self.awaiting_reply {
+            return;
+        }
+
+        self.clocks += 1;
+        if self.clocks < BIT_M_CYCLES {
+            return;
+        }
+        self.clocks = 0;
+
+        // Shift out MSB first, shift the incoming bit into the LSB
+        mem[0xFF01] = (mem[0xFF01] << 1) | (self.incoming >> 7);
+        self.incoming <<= 1;
+        self.bits_left -= 1;
+
+        if self.bits_left == 0 {
+            self.transferring = false;
+            mem[0xFF02] &= 0x7F;
+            mem[0xFF0F] |= 0x08; // Serial interrupt
+        }
+    }
+
+    fn poll_link(&mut self, mem: &mut Memory) {
+        while let Some(msg) = self.link.as_mut().and_then(|link| link.recv()) {
+            match msg {
+                // Partner drives the clock
+                LinkMessage::Transfer(byte) => {
+                    let sb = mem[0xFF01];
+                    if mem[0xFF02] & 0x81 == 0x80 {
+                        self.capture(sb);
+                        self.reply(sb);
+                        mem[0xFF01] = byte;
+                        mem[0xFF02] &= 0x7F;
+                        mem[0xFF0F] |= 0x08; // Serial interrupt
+                    } else {
+                        // Not ready for a transfer, partner reads 1s
+                        self.reply(0xFF);
+                    }
+                },
+                LinkMessage::Reply(byte) => {
+                    if self.awaiting_reply {
+                        self.incoming = byte;
+                        self.awaiting_reply = false;
+                    }
+                }
+            }
+        }
+    }
+
+    fn reply(&mut self, byte: u8) {
+        if let Some(link) = self.link.as_mut() {
+            link.send(LinkMessage::Reply(byte));
+        }
+    }
+}
+
+impl Default for Serial {
+    fn default() -> Serial {
+        Serial::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_internal_clock_transfer() {
+        let mut mem = Memory::new();
+        let mut serial = Serial::new();
+        mem[0xFF01] = 0x42;
+        mem[0xFF02] = 0x81;
+
+        for _ in 0..(BIT_M_CYCLES * 8 - 1) {
+            serial.tick(&mut mem);
+        }
+        assert_eq!(mem[0xFF02], 0x81, "Transfer finished early");
+        assert_eq!(mem[0xFF0F] & 0x08, 0);
+
+        serial.tick(&mut mem);
+        assert_eq!(mem[0xFF01], 0xFF);
+        assert_eq!(mem[0xFF02], 0x01);
+        assert_eq!(mem[0xFF0F] & 0x08, 0x08);
+    }
+
+    #[test]
+    fn test_output_capture() {
+        let mut mem = Memory::new();
+        let mut serial = Serial::new();
+
+        for byte in b"Passed" {
+            mem[0xFF01] = *byte;
+            mem[0xFF02] = 0x81;
+            while mem[0xFF02] & 0x80 != 0 {
+                serial.tick(&mut mem);
+            }
+        }
+        assert_eq!(serial.output(), b"Passed");
+    }
+
+    #[test]
+    fn test_external_clock_waits() {
+        let mut mem = Memory::new();
+        let mut serial = Serial::new();
+        mem[0xFF01] = 0x42;
+        mem[0xFF02] = 0x80;
+
+        for _ in 0..(BIT_M_CYCLES * 16) {
+            serial.tick(&mut mem);
+        }
+        assert_eq!(mem[0xFF01], 0x42);
+        assert_eq!(mem[0xFF02], 0x80);
+        assert_eq!(mem[0xFF0F] & 0x08, 0);
+    }
+}
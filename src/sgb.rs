@@ -0,0 +1,472 @@
+use crate::mmu::*;
+use crate::gpu::*;
+
+use log::debug;
+
+// Super Game Boy support.
+// Commands arrive as 16 byte packets sent bit by bit through the P1 select
+// lines: both low resets, then P14 low sends a 0 and P15 low a 1 with both
+// high in between. 128 data bits are followed by a 0 stop bit. The first
+// byte holds the command in bits 3-7 and the number of packets in bits 0-2.
+// Bulk data for the *_TRN commands is taken from the tiles shown on screen.
+// https://gbdev.io/pandocs/SGB_Functions.html
+
+pub const BORDER_WIDTH: usize = 256;
+pub const BORDER_HEIGHT: usize = 224;
+
+// Game Boy screen position inside the border
+const SCREEN_X: usize = 48;
+const SCREEN_Y: usize = 40;
+
+// Palettes are assigned per 8x8 cell of the screen
+const CELLS_X: usize = SCREEN_WIDTH / 8;
+const CELLS_Y: usize = SCREEN_HEIGHT / 8;
+
+const PACKET_BITS: usize = 128;
+const TRANSFER_SIZE: usize = 0x1000;
+const ATTR_FILE_SIZE: usize = 90;
+
+// Palette used until the game sets its own
+const DEFAULT_PALETTE: [u16; 4] = [0x67BF, 0x265B, 0x10B5, 0x2866];
+
+pub struct Sgb {
+    // Next bit of the packet being received, None until a reset pulse
+    bit: Option<usize>,
+    packet: [u8; 16],
+    packets: Vec<[u8; 16]>,
+    // Colour 0 is shared by all four palettes
+    palettes: [[u16; 4]; 4],
+    system_palettes: Vec<[u16; 4]>,
+    attr_map: [u8; CELLS_X * CELLS_Y],
+    attr_files: Vec<[u8; ATTR_FILE_SIZE]>,
+    // MASK_EN: 0 off, 1 freeze, 2 black, 3 colour 0
+    mask: u8,
+    frozen: Vec<u16>,
+    // SNES 4bpp tiles, 32x28 tile map and palettes 4-7 of the border
+    border_tiles: Vec<u8>,
+    border_map: Vec<u16>,
+    border_palettes: [[u16; 16]; 4],
+    // Controllers polled through MLT_REQ
+    players: usize,
+    player: usize
+}
+
+impl Sgb {
+    pub fn new() -> Sgb {
+        Sgb {
+            bit: None,
+            packet: [0; 16],
+            packets: Vec::new(),
+            palettes: [DEFAULT_PALETTE; 4],
+            system_palettes: vec![[0; 4]; 512],
+            attr_map: [0; CELLS_X * CELLS_Y],
+            attr_files: vec![[0; ATTR_FILE_SIZE]; 45],
+            mask: 0,
+            frozen: Vec::new(),
+            border_tiles: vec![0; 2 * TRANSFER_SIZE],
+            border_map: vec![0; 32 * 28],
+            border_palettes: [[0; 16]; 4],
+            players: 1,
+            player: 0
+        }
+    }
+
+    // Controller currently answering P1 reads
+    pub fn player(&self) -> usize {
+        self.player
+    }
+
+    // Colour of a DMG shade at a framebuffer index
+    pub fn color(&self, pixel: usize, shade: usize) -> u16 {
+        let cell = (pixel / SCREEN_WIDTH / 8) * CELLS_X + (pixel % SCREEN_WIDTH) / 8;
+        self.palettes[self.attr_map[cell] as usize][shade]
+    }
+
+    // Compose the screen and border as 0RGB for display, BORDER_WIDTH x BORDER_HEIGHT
+    pub fn render(&mut self, framebuffer: &[u16], color_correction: bool, buffer: &mut [u32]) {
+        if self.mask == 1 && self.frozen.is_empty() {
+            self.frozen = framebuffer.to_vec();
+        }
+
+        let backdrop = self.palettes[0][0];
+        for (i, out) in buffer.iter_mut().enumerate().take(BORDER_WIDTH * BORDER_HEIGHT) {
+            let (x, y) = (i % BORDER_WIDTH, i / BORDER_WIDTH);
+            let color = self.border_pixel(x, y)
+                .or_else(|| self.screen_pixel(framebuffer, x, y))
+                .unwrap_or(backdrop);
+            *out = rgb555_to_rgb888(color, color_correction);
+        }
+    }
+
+    fn screen_pixel(&self, framebuffer: &[u16], x: usize, y: usize) -> Option<u16> {
+        if !(SCREEN_X..SCREEN_X + SCREEN_WIDTH).contains(&x) || !(SCREEN_Y..SCREEN_Y + SCREEN_HEIGHT).contains(&y) {
+            return None;
+        }
+
+        let i = (y - SCREEN_Y) * SCREEN_WIDTH + x - SCREEN_X;
+        match self.mask {
+            1 => Some(self.frozen[i]),
+            2 => Some(0x0000),
+            3 => Some(self.palettes[0][0]),
+            _ => Some(framebuffer[i])
+        }
+    }
+
+    // Colour 0 of the border is transparent
+    fn border_pixel(&self, x: usize, y: usize) -> Option<u16> {
+        let entry = self.border_map[(y / 8) * 32 + x / 8];
+        let tile = (entry & 0xFF) as usize;
+        let palette = ((entry >> 10) & 0x03) as usize;
+        let row = if entry & 0x8000 != 0 { 7 - y % 8 } else { y % 8 };
+        let col = if entry & 0x4000 != 0 { 7 - x % 8 } else { x % 8 };
+
+        // Bitplanes 0 and 1 interleaved per row, then 2 and 3
+        let adr = tile * 32 + row * 2;
+        let bit = 7 - col;
+        let color_id = [adr, adr + 1, adr + 16, adr + 17].iter().enumerate()
+            .fold(0, |id, (plane, adr)| id | ((self.border_tiles[*adr] >> bit) & 0x01) << plane);
+
+        match color_id {
+            0 => None,
+            _ => Some(self.border_palettes[palette][color_id as usize])
+        }
+    }
+
+    // Feed a change of the P1 select lines, returns the data of a complete command
+    fn receive(&mut self, old: u8, select: u8) -> Option<Vec<u8>> {
+        // P15 going high outside of a packet moves on to the next controller
+        if self.bit.is_none() && old & 0x20 == 0 && select & 0x20 != 0 {
+            self.player = (self.player + 1) % self.players;
+        }
+
+        if select == 0x00 {
+            self.bit = Some(0);
+            self.packet = [0; 16];
+            return None;
+        }
+
+        // Bits are only latched when leaving the idle state
+        if select == 0x30 || old != 0x30 {
+            return None;
+        }
+
+        let bit = self.bit?;
+        let value = select == 0x10;
+        if bit < PACKET_BITS {
+            self.packet[bit / 8] |= (value as u8) << (bit % 8);
+            self.bit = Some(bit + 1);
+            return None;
+        }
+
+        // Stop bit
+        self.bit = None;
+        if value {
+            self.packets.clear();
+            return None;
+        }
+        self.packets.push(self.packet);
+        let len = (self.packets[0][0] & 0x07).max(1) as usize;
+        match self.packets.len() < len {
+            true => None,
+            false => Some(std::mem::take(&mut self.packets).concat())
+        }
+    }
+
+    fn execute(&mut self, data: &[u8], vram: &[u8]) {
+        match data[0] >> 3 {
+            0x00 => self.set_palettes(0, 1, data),
+            0x01 => self.set_palettes(2, 3, data),
+            0x02 => self.set_palettes(0, 3, data),
+            0x03 => self.set_palettes(1, 2, data),
+            0x04 => self.attr_blk(data),
+            0x05 => self.attr_lin(data),
+            0x06 => self.attr_div(data),
+            0x07 => self.attr_chr(data),
+            0x0A => self.pal_set(data),
+            // PAL_TRN
+            0x0B => {
+                self.system_palettes = vram.chunks_exact(8).map(colors).collect();
+            },
+            // MLT_REQ
+            0x11 => {
+                self.players = match data[1] & 0x03 {
+                    1 => 2,
+                    3 => 4,
+                    _ => 1
+                };
+                self.player = 0;
+            },
+            // CHR_TRN
+            0x13 => {
+                let offset = (data[1] & 0x01) as usize * TRANSFER_SIZE;
+                self.border_tiles[offset..offset + TRANSFER_SIZE].copy_from_slice(vram);
+            },
+            // PCT_TRN
+            0x14 => {
+                let len = self.border_map.len();
+                self.border_map = vram[..len * 2].chunks_exact(2).map(|c| c[0] as u16 | (c[1] as u16) << 8).collect();
+                for (palette, data) in self.border_palettes.iter_mut().zip(vram[0x800..0x880].chunks_exact(32)) {
+                    *palette = colors(data);
+                }
+            },
+            // ATTR_TRN
+            0x15 => {
+                for (file, data) in self.attr_files.iter_mut().zip(vram.chunks_exact(ATTR_FILE_SIZE)) {
+                    file.copy_from_slice(data);
+                }
+            },
+            // ATTR_SET
+            0x16 => {
+                self.apply_attr_file(data[1] & 0x3F);
+                if data[1] & 0x40 != 0 {
+                    self.set_mask(0);
+                }
+            },
+            // MASK_EN
+            0x17 => self.set_mask(data[1] & 0x03),
+            command => debug!("Unhandled SGB command {:#04X}", command)
+        }
+    }
+
+    // PAL01, PAL23, PAL03, PAL12 - shared colour 0 followed by colours 1-3 of each
+    fn set_palettes(&mut self, a: usize, b: usize, data: &[u8]) {
+        let c: [u16; 7] = colors(&data[1..15]);
+        self.palettes[a][1..].copy_from_slice(&c[1..4]);
+        self.palettes[b][1..].copy_from_slice(&c[4..7]);
+        self.palettes.iter_mut().for_each(|palette| palette[0] = c[0]);
+    }
+
+    // Palettes inside, on the border of and outside a set of rectangles
+    fn attr_blk(&mut self, data: &[u8]) {
+        let count = (data[1] & 0x1F) as usize;
+        for set in data[2..].chunks_exact(6).take(count) {
+            let control = set[0] & 0x07;
+            let inside = set[1] & 0x03;
+            let outside = (set[1] >> 4) & 0x03;
+            // Changing only the inside or outside takes the border along
+            let border = match control {
+                0x01 => inside,
+                0x04 => outside,
+                _ => (set[1] >> 2) & 0x03
+            };
+            let (x1, y1, x2, y2) = (set[2] & 0x1F, set[3] & 0x1F, set[4] & 0x1F, set[5] & 0x1F);
+
+            self.fill(|x, y| {
+                let within = x >= x1 && x <= x2 && y >= y1 && y <= y2;
+                let inner = x > x1 && x < x2 && y > y1 && y < y2;
+                match (within, inner) {
+                    (_, true) if control & 0x01 != 0 => Some(inside),
+                    (true, false) if control & 0x02 != 0 || control == 0x01 || control == 0x04 => Some(border),
+                    (false, _) if control & 0x04 != 0 => Some(outside),
+                    _ => None
+                }
+            });
+        }
+    }
+
+    // Palettes of whole rows or columns
+    fn attr_lin(&mut self, data: &[u8]) {
+        let count = data[1] as usize;
+        for &line in data[2..].iter().take(count) {
+            let n = line & 0x1F;
+            let palette = (line >> 5) & 0x03;
+            let horizontal = line & 0x80 != 0;
+            self.fill(|x, y| match (horizontal && y == n) || (!horizontal && x == n) {
+                true => Some(palette),
+                false => None
+            });
+        }
+    }
+
+    // Split the screen in two at a row or column
+    fn attr_div(&mut self, data: &[u8]) {
+        let after = data[1] & 0x03;
+        let before = (data[1] >> 2) & 0x03;
+        let on = (data[1] >> 4) & 0x03;
+        let horizontal = data[1] & 0x40 != 0;
+        let n = data[2] & 0x1F;
+        self.fill(|x, y| {
+            let pos = if horizontal { y } else { x };
+            Some(match pos.cmp(&n) {
+                std::cmp::Ordering::Less => before,
+                std::cmp::Ordering::Equal => on,
+                std::cmp::Ordering::Greater => after
+            })
+        });
+    }
+
+    // Palettes of consecutive cells, four per byte
+    fn attr_chr(&mut self, data: &[u8]) {
+        let (mut x, mut y) = ((data[1] & 0x1F) as usize, (data[2] & 0x1F) as usize);
+        let count = (data[3] as usize | (data[4] as usize) << 8).min(CELLS_X * CELLS_Y);
+        let vertical = data[5] & 0x01 != 0;
+
+        for i in 0..count {
+            let Some(&byte) = data.get(6 + i / 4) else { break };
+            if x >= CELLS_X || y >= CELLS_Y {
+                break;
+            }
+            self.attr_map[y * CELLS_X + x] = (byte >> (6 - (i % 4) * 2)) & 0x03;
+
+            match vertical {
+                false => { x += 1; if x == CELLS_X { x = 0; y += 1; } },
+                true => { y += 1; if y == CELLS_Y { y = 0; x += 1; } }
+            }
+        }
+    }
+
+    // Palettes from the ones sent with PAL_TRN, optionally with an attribute file
+    fn pal_set(&mut self, data: &[u8]) {
+        for (i, palette) in self.palettes.iter_mut().enumerate() {
+            let index = (data[1 + i * 2] as usize | (data[2 + i * 2] as usize) << 8) & 0x1FF;
+            *palette = self.system_palettes[index];
+        }
+        let color0 = self.palettes[0][0];
+        self.palettes.iter_mut().for_each(|palette| palette[0] = color0);
+
+        if data[9] & 0x80 != 0 {
+            self.apply_attr_file(data[9] & 0x3F);
+        }
+        if data[9] & 0x40 != 0 {
+            self.set_mask(0);
+        }
+    }
+
+    fn apply_attr_file(&mut self, file: u8) {
+        let Some(file) = self.attr_files.get(file as usize) else { return };
+        for (i, cell) in self.attr_map.iter_mut().enumerate() {
+            *cell = (file[i / 4] >> (6 - (i % 4) * 2)) & 0x03;
+        }
+    }
+
+    fn set_mask(&mut self, mask: u8) {
+        self.mask = mask;
+        self.frozen.clear();
+    }
+
+    fn fill(&mut self, palette_at: impl Fn(u8, u8) -> Option<u8>) {
+        for (i, cell) in self.attr_map.iter_mut().enumerate() {
+            if let Some(palette) = palette_at((i % CELLS_X) as u8, (i / CELLS_X) as u8) {
+                *cell = palette;
+            }
+        }
+    }
+}
+
+impl Default for Sgb {
+    fn default() -> Sgb {
+        Sgb::new()
+    }
+}
+
+// Little endian RGB555 colours
+fn colors<const N: usize>(data: &[u8]) -> [u16; N] {
+    let mut colors = [0; N];
+    for (color, c) in colors.iter_mut().zip(data.chunks_exact(2)) {
+        *color = c[0] as u16 | (c[1] as u16) << 8;
+    }
+    colors
+}
+
+// 4 KiB of tile data in the order the tiles appear on screen, 20 per row
+fn vram_transfer(mem: &Memory) -> Vec<u8> {
+    let lcdc = mem[0xFF40];
+    let map_base = if lcdc & 0x08 != 0 { 0x9C00 } else { 0x9800 };
+    (0..TRANSFER_SIZE / 16).flat_map(|i| {
+        let tile = mem[map_base + (i / CELLS_X) * 32 + i % CELLS_X];
+        let adr = tile_adr(lcdc, tile) as usize;
+        mem[adr..adr + 16].iter().copied()
+    }).collect()
+}
+
+// Called by P1 writes when running on an SGB
+pub fn write_sgb(old: u8, select: u8, mem: &mut Memory) {
+    let Some(data) = mem.sgb.as_mut().and_then(|sgb| sgb.receive(old, select)) else { return };
+
+    let vram = match data[0] >> 3 {
+        0x0B | 0x13 | 0x14 | 0x15 => vram_transfer(mem),
+        _ => Vec::new()
+    };
+    if let Some(sgb) = mem.sgb.as_mut() {
+        sgb.execute(&data, &vram);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::joypad::*;
+    use crate::model::*;
+
+    fn sgb_memory() -> Memory {
+        let mut mem = Memory::with_model(Model::Sgb, false);
+        mem.sgb = Some(Box::new(Sgb::new()));
+        write_joypad(0x30, &mut mem);
+        mem
+    }
+
+    fn send_packet(mem: &mut Memory, packet: &[u8; 16]) {
+        write_joypad(0x00, mem);
+        write_joypad(0x30, mem);
+        for i in 0..PACKET_BITS {
+            let bit = (packet[i / 8] >> (i % 8)) & 0x01;
+            write_joypad(if bit == 1 { 0x10 } else { 0x20 }, mem);
+            write_joypad(0x30, mem);
+        }
+        write_joypad(0x20, mem);
+        write_joypad(0x30, mem);
+    }
+
+    #[test]
+    fn test_pal01() {
+        let mut mem = sgb_memory();
+        let mut packet = [0; 16];
+        packet[0] = 0x01;
+        // Colour 0 white, palette 1 colour 3 pure red
+        packet[1..3].copy_from_slice(&[0xFF, 0x7F]);
+        packet[13..15].copy_from_slice(&[0x1F, 0x00]);
+        send_packet(&mut mem, &packet);
+
+        let sgb = mem.sgb.as_ref().unwrap();
+        assert_eq!(sgb.palettes[1], [0x7FFF, 0, 0, 0x001F]);
+        assert_eq!(sgb.palettes[3][0], 0x7FFF);
+    }
+
+    #[test]
+    fn test_attr_blk() {
+        let mut mem = sgb_memory();
+        let mut packet = [0; 16];
+        packet[0] = 0x04 << 3 | 0x01;
+        packet[1] = 1;
+        // Inside only, palette 2, cells 1,1 to 3,3
+        packet[2..8].copy_from_slice(&[0x01, 0x02, 1, 1, 3, 3]);
+        send_packet(&mut mem, &packet);
+
+        let sgb = mem.sgb.as_mut().unwrap();
+        sgb.palettes[2][1] = 0x1234;
+        assert_eq!(sgb.attr_map[2 * CELLS_X + 2], 2);
+        // The border follows the inside
+        assert_eq!(sgb.attr_map[CELLS_X + 1], 2);
+        assert_eq!(sgb.attr_map[0], 0);
+        assert_eq!(sgb.color(16 * SCREEN_WIDTH + 16, 1), 0x1234);
+    }
+
+    #[test]
+    fn test_mlt_req() {
+        let mut mem = sgb_memory();
+        let mut packet = [0; 16];
+        packet[0] = 0x11 << 3 | 0x01;
+        packet[1] = 0x01;
+        send_packet(&mut mem, &packet);
+        assert_eq!(read_joypad(&mem) & 0x0F, 0x0F);
+
+        set_button(&mut mem, 1, Button::Start, true);
+        write_joypad(0x10, &mut mem);
+        write_joypad(0x30, &mut mem);
+        assert_eq!(read_joypad(&mem) & 0x0F, 0x0E);
+        write_joypad(0x10, &mut mem);
+        assert_eq!(read_joypad(&mem) & 0x0F, 0x07);
+        write_joypad(0x30, &mut mem);
+        assert_eq!(read_joypad(&mem) & 0x0F, 0x0F);
+    }
+}
@@ -0,0 +1,231 @@
+// Link cable over TCP between two rustboy processes.
+//
+// Both sides open with a 5 byte hello, "RBLK" followed by the protocol
+// version. After that every message is two bytes, a tag and a data byte:
+//
+//   0x01 nn  Transfer - sender drives the clock and shifts out nn
+//   0x02 nn  Reply    - sender is on external clock and shifted out nn
+//
+// Which side is the clock master is decided per transfer by SC bit 0, so the
+// protocol is symmetric. The master's serial controller holds the transfer
+// until the reply arrives, which keeps both sides in sync regardless of the
+// network latency.
+
+use crate::serial::*;
+
+use log::warn;
+use std::io;
+use std::io::{ ErrorKind, Read, Write };
+use std::net::{ TcpListener, TcpStream, ToSocketAddrs };
+
+const MAGIC: &[u8; 4] = b"RBLK";
+const VERSION: u8 = 1;
+
+const TAG_TRANSFER: u8 = 0x01;
+const TAG_REPLY: u8 = 0x02;
+
+// Serial ticks every m-cycle, only hit the socket every so often.
+// One bit takes 128 m-cycles on internal clock.
+const POLL_INTERVAL: u16 = 32;
+
+pub struct TcpLink {
+    stream: TcpStream,
+    inbox: Vec<u8>,
+    outbox: Vec<u8>,
+    polls: u16,
+    closed: bool,
+    // Sent a transfer the partner hasn't replied to yet
+    awaiting_reply: bool,
+    // Answer for a transfer the partner went away on
+    unplugged_reply: Option<LinkMessage>
+}
+
+impl TcpLink {
+    // Wait for the partner to connect
+    pub fn listen<A: ToSocketAddrs>(addr: A) -> io::Result<TcpLink> {
+        let listener = TcpListener::bind(addr)?;
+        let (stream, _) = listener.accept()?;
+        TcpLink::from_stream(stream)
+    }
+
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<TcpLink> {
+        TcpLink::from_stream(TcpStream::connect(addr)?)
+    }
+
+    pub fn from_stream(mut stream: TcpStream) -> io::Result<TcpLink> {
+        stream.set_nodelay(true)?;
+
+        let mut hello = [0; 5];
+        stream.write_all(MAGIC)?;
+        stream.write_all(&[VERSION])?;
+        stream.read_exact(&mut hello)?;
+        if &hello[0..4] != MAGIC || hello[4] != VERSION {
+            return Err(io::Error::new(ErrorKind::InvalidData, "not a rustboy link partner"));
+        }
+
+        stream.set_nonblocking(true)?;
+        Ok(TcpLink {
+            stream,
+            inbox: Vec::new(),
+            outbox: Vec::new(),
+            polls: 0,
+            closed: false,
+            awaiting_reply: false,
+            unplugged_reply: None
+        })
+    }
+
+    fn close(&mut self, why: &str) {
+        if !self.closed {
+            warn!("Link cable disconnected: {}", why);
+            self.closed = true;
+        }
+        // A transfer in flight is answered like one sent unplugged
+        if std::mem::take(&mut self.awaiting_reply) {
+            self.unplugged_reply = Some(LinkMessage::Reply(0xFF));
+        }
+    }
+
+    fn flush(&mut self) {
+        while !self.outbox.is_empty() && !self.closed {
+            match self.stream.write(&self.outbox) {
+                Ok(0) => self.close("connection closed"),
+                Ok(n) => { self.outbox.drain(..n); },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {},
+                Err(e) => self.close(&e.to_string())
+            }
+        }
+    }
+
+    fn fill(&mut self) {
+        let mut buf = [0; 64];
+        while !self.closed {
+            match self.stream.read(&mut buf) {
+                Ok(0) => self.close("connection closed"),
+                Ok(n) => self.inbox.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {},
+                Err(e) => self.close(&e.to_string())
+            }
+        }
+    }
+}
+
+impl SerialLink for TcpLink {
+    fn send(&mut self, msg: LinkMessage) {
+        if self.closed {
+            // Nobody on the other end, the data line reads high
+            if let LinkMessage::Transfer(_) = msg {
+                self.unplugged_reply = Some(LinkMessage::Reply(0xFF));
+            }
+            return;
+        }
+        if let LinkMessage::Transfer(_) = msg {
+            self.awaiting_reply = true;
+        }
+
+        let bytes = match msg {
+            LinkMessage::Transfer(byte) => [TAG_TRANSFER, byte],
+            LinkMessage::Reply(byte) => [TAG_REPLY, byte]
+        };
+        self.outbox.extend_from_slice(&bytes);
+        self.flush();
+    }
+
+    fn recv(&mut self) -> Option<LinkMessage> {
+        if let Some(msg) = self.unplugged_reply.take() {
+            return Some(msg);
+        }
+
+        if self.inbox.len() < 2 {
+            self.polls += 1;
+            if self.polls < POLL_INTERVAL {
+                return None;
+            }
+            self.polls = 0;
+            self.flush();
+            self.fill();
+            if self.inbox.len() < 2 {
+                return self.unplugged_reply.take();
+            }
+        }
+
+        let msg = match self.inbox[0] {
+            TAG_TRANSFER => LinkMessage::Transfer(self.inbox[1]),
+            TAG_REPLY => LinkMessage::Reply(self.inbox[1]),
+            tag => {
+                self.inbox.clear();
+                self.close(&format!("unknown message {:#04x}", tag));
+                return None;
+            }
+        };
+        self.inbox.drain(..2);
+        if let LinkMessage::Reply(_) = msg {
+            self.awaiting_reply = false;
+        }
+        Some(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    fn tcp_link_pair() -> (TcpLink, TcpLink) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || TcpLink::connect(addr).unwrap());
+        let (stream, _) = listener.accept().unwrap();
+        let server = TcpLink::from_stream(stream).unwrap();
+        (server, client.join().unwrap())
+    }
+
+    fn recv_blocking(link: &mut TcpLink) -> LinkMessage {
+        loop {
+            if let Some(msg) = link.recv() {
+                return msg;
+            }
+            thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn test_messages_round_trip() {
+        let (mut a, mut b) = tcp_link_pair();
+
+        a.send(LinkMessage::Transfer(0x42));
+        assert_eq!(recv_blocking(&mut b), LinkMessage::Transfer(0x42));
+        b.send(LinkMessage::Reply(0x99));
+        assert_eq!(recv_blocking(&mut a), LinkMessage::Reply(0x99));
+    }
+
+    #[test]
+    fn test_disconnected_partner_reads_ones() {
+        let (mut a, b) = tcp_link_pair();
+        drop(b);
+
+        // Wait for the hangup to be noticed
+        while !a.closed {
+            assert_eq!(a.recv(), None);
+            thread::yield_now();
+        }
+        a.send(LinkMessage::Transfer(0x42));
+        assert_eq!(a.recv(), Some(LinkMessage::Reply(0xFF)));
+    }
+
+    #[test]
+    fn test_partner_dropped_mid_transfer() {
+        let (mut a, mut b) = tcp_link_pair();
+        a.send(LinkMessage::Transfer(0x42));
+        assert_eq!(recv_blocking(&mut b), LinkMessage::Transfer(0x42));
+        drop(b);
+
+        // The outstanding transfer still gets its answer
+        assert_eq!(recv_blocking(&mut a), LinkMessage::Reply(0xFF));
+        assert!(a.closed);
+        assert_eq!(a.recv(), None);
+    }
+}
@@ -0,0 +1,81 @@
+use crate::mmu::*;
+
+// DIV and TIMA, clocked by the CPU so they follow double speed
+#[derive(Debug, Clone, Copy)]
+pub struct Timer;
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer
+    }
+
+    pub fn tick(&mut self, mem: &mut Memory) {
+        // DIV is the upper byte of a 16-bit counter running at 4 MHz
+        let old = mem.div;
+        mem.div = mem.div.wrapping_add(4);
+        mem[0xFF04] = (mem.div >> 8) as u8;
+
+        let tac = mem[0xFF07];
+        if tac & 0x04 == 0 {
+            return;
+        }
+
+        // TIMA counts falling edges of a divider bit selected by TAC
+        let bit = match tac & 0x03 {
+            0 => 9, // 4096 Hz
+            1 => 3, // 262144 Hz
+            2 => 5, // 65536 Hz
+            _ => 7  // 16384 Hz
+        };
+        if (old >> bit) & 0x01 == 1 && (mem.div >> bit) & 0x01 == 0 {
+            let (tima, overflow) = mem[0xFF05].overflowing_add(1);
+            mem[0xFF05] = tima;
+            if overflow {
+                mem[0xFF05] = mem[0xFF06];
+                mem[0xFF0F] |= 0x04; // Timer interrupt
+            }
+        }
+    }
+}
+
+impl Default for Timer {
+    fn default() -> Timer {
+        Timer::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_div() {
+        let mut mem = Memory::new();
+        let mut timer = Timer::new();
+        for _ in 0..64 {
+            timer.tick(&mut mem);
+        }
+        assert_eq!(mem[0xFF04], 1);
+
+        write_byte(0xFF04, 0x55, &mut mem);
+        assert_eq!(mem[0xFF04], 0);
+        assert_eq!(mem.div, 0);
+    }
+
+    #[test]
+    fn test_tima_overflow() {
+        let mut mem = Memory::new();
+        let mut timer = Timer::new();
+        mem[0xFF05] = 0xFF;
+        mem[0xFF06] = 0x42;
+        mem[0xFF07] = 0x05; // 262144 Hz, every 4 m-cycles
+
+        for _ in 0..3 {
+            timer.tick(&mut mem);
+        }
+        assert_eq!(mem[0xFF05], 0xFF);
+        timer.tick(&mut mem);
+        assert_eq!(mem[0xFF05], 0x42);
+        assert_eq!(mem[0xFF0F] & 0x04, 0x04);
+    }
+}
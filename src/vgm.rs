@@ -0,0 +1,134 @@
+use log::warn;
+use std::fs::File;
+use std::io;
+use std::io::{ BufWriter, Seek, SeekFrom, Write };
+use std::path::Path;
+
+// Logs APU register writes to a VGM 1.71 file, which players replay on their
+// own Game Boy sound emulation. Time is counted in 44.1 kHz samples, the
+// writes are timestamped in normal speed m-cycles.
+
+const VGM_RATE: u64 = 44100;
+const M_CYCLES_PER_SECOND: u64 = 1 << 20;
+const HEADER_LEN: u32 = 0x100;
+const DMG_CLOCK: u32 = 4194304;
+
+// Commands
+const GB_WRITE: u8 = 0xB3;
+const WAIT: u8 = 0x61;
+const WAIT_60HZ: u8 = 0x62;
+const WAIT_50HZ: u8 = 0x63;
+const END: u8 = 0x66;
+
+pub struct VgmWriter {
+    file: BufWriter<File>,
+    start: u64,
+    // Time of the last write, and samples waited so far
+    last: u64,
+    samples: u64,
+    data_len: u32,
+    finished: bool
+}
+
+impl VgmWriter {
+    // `cycles` is the current time, which becomes the start of the file
+    pub fn create<P: AsRef<Path>>(path: P, cycles: u64) -> io::Result<VgmWriter> {
+        let mut file = BufWriter::new(File::create(path)?);
+        let mut header = [0u8; HEADER_LEN as usize];
+        header[0x00..0x04].copy_from_slice(b"Vgm ");
+        header[0x08..0x0C].copy_from_slice(&0x171u32.to_le_bytes());
+        // Data offset, relative to the field
+        header[0x34..0x38].copy_from_slice(&(HEADER_LEN - 0x34).to_le_bytes());
+        header[0x80..0x84].copy_from_slice(&DMG_CLOCK.to_le_bytes());
+        file.write_all(&header)?;
+
+        Ok(VgmWriter { file, start: cycles, last: cycles, samples: 0, data_len: 0, finished: false })
+    }
+
+    // A write to `adr` in 0xFF10-0xFF3F at m-cycle `cycles`
+    pub fn write(&mut self, cycles: u64, adr: u16, val: u8) {
+        self.last = cycles;
+        self.wait_until(cycles);
+        self.emit(&[GB_WRITE, (adr - 0xFF10) as u8, val]);
+    }
+
+    fn wait_until(&mut self, cycles: u64) {
+        let target = (cycles.saturating_sub(self.start)) * VGM_RATE / M_CYCLES_PER_SECOND;
+        while self.samples < target {
+            let wait = (target - self.samples).min(0xFFFF);
+            match wait {
+                735 => self.emit(&[WAIT_60HZ]),
+                882 => self.emit(&[WAIT_50HZ]),
+                1..=16 => self.emit(&[0x70 + wait as u8 - 1]),
+                _ => self.emit(&[WAIT, wait as u8, (wait >> 8) as u8])
+            }
+            self.samples += wait;
+        }
+    }
+
+    fn emit(&mut self, data: &[u8]) {
+        match self.file.write_all(data) {
+            Ok(()) => self.data_len += data.len() as u32,
+            Err(why) => warn!("couldn't write VGM data: {}", why)
+        }
+    }
+
+    // End the file at m-cycle `cycles` and fill in the header. Dropping the
+    // writer ends it at the last write.
+    pub fn finish(&mut self, cycles: u64) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        self.wait_until(cycles);
+        self.file.write_all(&[END])?;
+        self.data_len += 1;
+
+        self.file.seek(SeekFrom::Start(0x04))?;
+        self.file.write_all(&(HEADER_LEN + self.data_len - 4).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(0x18))?;
+        self.file.write_all(&(self.samples as u32).to_le_bytes())?;
+        self.file.flush()
+    }
+}
+
+impl Drop for VgmWriter {
+    fn drop(&mut self) {
+        if let Err(why) = self.finish(self.last) {
+            warn!("couldn't finish VGM file: {}", why);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_vgm_writer() {
+        let path = std::env::temp_dir().join(format!("rustboy-test-{}.vgm", std::process::id()));
+        let start = 1000;
+        let mut vgm = VgmWriter::create(&path, start).unwrap();
+        vgm.write(start, 0xFF26, 0x80);
+        // 1/60 s later
+        vgm.write(start + M_CYCLES_PER_SECOND / 60 + 20, 0xFF30, 0x12);
+        vgm.write(start + M_CYCLES_PER_SECOND / 60 + 200, 0xFF12, 0xF0);
+        vgm.finish(start + M_CYCLES_PER_SECOND).unwrap();
+
+        let data = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let field = |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+        assert_eq!(&data[0..4], b"Vgm ");
+        assert_eq!(field(0x04) as usize, data.len() - 4);
+        assert_eq!(field(0x08), 0x171);
+        assert_eq!(field(0x18), 44100);
+        assert_eq!(field(0x80), DMG_CLOCK);
+        assert_eq!(&data[0x100..], &[
+            GB_WRITE, 0x16, 0x80,
+            WAIT_60HZ, GB_WRITE, 0x20, 0x12,
+            0x77, GB_WRITE, 0x02, 0xF0,
+            WAIT, 0x5D, 0xA9, END
+        ]);
+    }
+}